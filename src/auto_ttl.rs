@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read};
+use std::mem::size_of;
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use log::{debug, trace};
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Max IPv4 header with options and TCP ports
+const SNAP_LEN: u32 = 64;
+/// IPv6 raw socket receives data without IPv6 header
+const SNAP_LEN_V6: u32 = 20;
+const TCP_FLAGS_SYN_ACK: u32 = 0x12;
+const MAX_CACHED_HOSTS: usize = 4096;
+
+/// Auto TTL of every delta, they share SYN_ACKS
+static AUTO_TTLS: Mutex<Vec<Arc<AutoTtl>>> = Mutex::new(Vec::new());
+/// Raw sockets copy every SYN-ACK of the host, so they are opened once per process
+static SYN_ACKS: Mutex<Option<Arc<SynAcks>>> = Mutex::new(None);
+
+/// Classic BPF which passes only TCP segments with SYN and ACK flags from IPv4 packets
+const SYN_ACK_FILTER: [libc::sock_filter; 6] = [
+  // ldxb 4*([0]&0xf)
+  bpf(libc::BPF_LDX | libc::BPF_B | libc::BPF_MSH, 0, 0, 0),
+  // ldb [x+13]
+  bpf(libc::BPF_LD | libc::BPF_B | libc::BPF_IND, 0, 0, 13),
+  // and #0x12
+  bpf(libc::BPF_ALU | libc::BPF_AND | libc::BPF_K, 0, 0, TCP_FLAGS_SYN_ACK),
+  // jeq #0x12, 0, 1
+  bpf(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, 0, 1, TCP_FLAGS_SYN_ACK),
+  bpf(libc::BPF_RET | libc::BPF_K, 0, 0, SNAP_LEN),
+  bpf(libc::BPF_RET | libc::BPF_K, 0, 0, 0),
+];
+
+/// The same for IPv6 raw socket
+const SYN_ACK_FILTER_V6: [libc::sock_filter; 5] = [
+  // ldb [13]
+  bpf(libc::BPF_LD | libc::BPF_B | libc::BPF_ABS, 0, 0, 13),
+  bpf(libc::BPF_ALU | libc::BPF_AND | libc::BPF_K, 0, 0, TCP_FLAGS_SYN_ACK),
+  bpf(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, 0, 1, TCP_FLAGS_SYN_ACK),
+  bpf(libc::BPF_RET | libc::BPF_K, 0, 0, SNAP_LEN_V6),
+  bpf(libc::BPF_RET | libc::BPF_K, 0, 0, 0),
+];
+
+const fn bpf(code: u32, jt: u8, jf: u8, k: u32) -> libc::sock_filter {
+  libc::sock_filter{ code: code as u16, jt, jf, k }
+}
+
+/// Estimates hop count to servers from TTL of their SYN-ACK
+/// and sets fake TTL to hops minus delta. Needs root for raw socket
+pub struct AutoTtl {
+  delta: u8,
+  syn_acks: Arc<SynAcks>
+}
+
+/// Raw sockets which receive SYN-ACKs and hops to their senders.
+/// Queues of sockets are drained by separate thread, so they don't fill up with SYN-ACKs of other connections
+struct SynAcks {
+  sock: Socket,
+  /// None if IPv6 is disabled
+  sock_v6: Option<Socket>,
+  hops: Mutex<HashMap<IpAddr, u8>>
+}
+
+impl fmt::Debug for AutoTtl {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("AutoTtl").field("delta", &self.delta).finish()
+  }
+}
+
+impl AutoTtl {
+  /// Returns auto TTL shared by all profiles with this delta
+  pub fn shared(delta: u8) -> io::Result<Arc<Self>> {
+    let mut auto_ttls = AUTO_TTLS.lock().unwrap();
+    if let Some(auto_ttl) = auto_ttls.iter().find(|auto_ttl| auto_ttl.delta == delta) { return Ok(auto_ttl.clone()); }
+    let auto_ttl = Arc::new(Self{ delta, syn_acks: SynAcks::shared()? });
+    auto_ttls.push(auto_ttl.clone());
+    Ok(auto_ttl)
+  }
+
+  /// Fake TTL for the peer of connected socket or None if SYN-ACK from it wasn't seen
+  pub fn fake_ttl(&self, fd: RawFd) -> Option<u32> {
+    let sock = unsafe { Socket::from_raw_fd(fd) };
+    let peer = sock.peer_addr();
+    let _ = sock.into_raw_fd();
+    let peer = peer.ok()?.as_socket()?.ip().to_canonical();
+    // SYN-ACK of this connection may be still queued
+    let peer_hops = *self.syn_acks.read_all().get(&peer)?;
+    let ttl = peer_hops.saturating_sub(self.delta).max(1);
+    debug!("{peer} is {peer_hops} hops away, fake ttl: {ttl}");
+    Some(ttl as u32)
+  }
+}
+
+impl SynAcks {
+  fn shared() -> io::Result<Arc<Self>> {
+    let mut syn_acks = SYN_ACKS.lock().unwrap();
+    if let Some(syn_acks) = syn_acks.as_ref() { return Ok(syn_acks.clone()); }
+    let sock = syn_ack_socket(Domain::IPV4, &SYN_ACK_FILTER)?;
+    let sock_v6 = syn_ack_socket(Domain::IPV6, &SYN_ACK_FILTER_V6)
+      .and_then(|sock| {
+        set_int_opt(sock.as_raw_fd(), libc::IPPROTO_IPV6, libc::IPV6_RECVHOPLIMIT, 1)?;
+        Ok(sock)
+      })
+      .inspect_err(|e| debug!("auto ttl works only for IPv4: {e}"))
+      .ok();
+    let shared = Arc::new(Self{ sock, sock_v6, hops: Mutex::new(HashMap::new()) });
+    let drained = shared.clone();
+    thread::Builder::new().name("auto-ttl".into()).spawn(move || drained.drain())?;
+    *syn_acks = Some(shared.clone());
+    Ok(shared)
+  }
+
+  /// Waits for SYN-ACKs and reads them as they come
+  fn drain(&self) {
+    let mut fds = vec![libc::pollfd{ fd: self.sock.as_raw_fd(), events: libc::POLLIN, revents: 0 }];
+    if let Some(sock_v6) = &self.sock_v6 { fds.push(libc::pollfd{ fd: sock_v6.as_raw_fd(), events: libc::POLLIN, revents: 0 }); }
+    loop {
+      if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, -1) } < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() == io::ErrorKind::Interrupted { continue; }
+        debug!("auto ttl poll: {e}");
+        return;
+      }
+      drop(self.read_all());
+    }
+  }
+
+  /// Reads all queued SYN-ACKs of both sockets and returns hops
+  fn read_all(&self) -> MutexGuard<'_, HashMap<IpAddr, u8>> {
+    let mut hops = self.hops.lock().unwrap();
+    read_syn_acks(&self.sock, &mut hops);
+    if let Some(sock_v6) = &self.sock_v6 { read_syn_acks_v6(sock_v6, &mut hops); }
+    hops
+  }
+}
+
+/// Reads all SYN-ACKs queued on IPv4 raw socket
+fn read_syn_acks(sock: &Socket, hops: &mut HashMap<IpAddr, u8>) {
+  let mut buf = [0u8; SNAP_LEN as usize];
+  loop {
+    let n = match (&*sock).read(&mut buf) {
+      Ok(n) => n,
+      Err(e) => {
+        if e.kind() != io::ErrorKind::WouldBlock { debug!("auto ttl raw socket: {e}"); }
+        break;
+      }
+    };
+    if n < 20 { continue; }
+    let src = IpAddr::V4(Ipv4Addr::new(buf[12], buf[13], buf[14], buf[15]));
+    insert_hops(hops, src, buf[8]);
+  }
+}
+
+/// Reads SYN-ACKs from IPv6 raw socket, hop limit is taken from control message
+fn read_syn_acks_v6(sock: &Socket, hops: &mut HashMap<IpAddr, u8>) {
+  let mut buf = [0u8; SNAP_LEN_V6 as usize];
+  let mut cmsg_buf = [0u64; 8];
+  loop {
+    let mut src: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+    let mut iov = libc::iovec{ iov_base: buf.as_mut_ptr() as _, iov_len: buf.len() };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_name = &mut src as *mut _ as _;
+    msg.msg_namelen = size_of::<libc::sockaddr_in6>() as _;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as _;
+    msg.msg_controllen = size_of::<[u64; 8]>() as _;
+    if unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, libc::MSG_DONTWAIT) } < 0 {
+      let e = io::Error::last_os_error();
+      if e.kind() != io::ErrorKind::WouldBlock { debug!("auto ttl raw socket: {e}"); }
+      break;
+    }
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+      let hdr = unsafe { &*cmsg };
+      if hdr.cmsg_level == libc::IPPROTO_IPV6 && hdr.cmsg_type == libc::IPV6_HOPLIMIT {
+        let hop_limit = unsafe { (libc::CMSG_DATA(cmsg) as *const libc::c_int).read_unaligned() };
+        insert_hops(hops, IpAddr::from(src.sin6_addr.s6_addr), hop_limit as u8);
+      }
+      cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+  }
+}
+
+fn insert_hops(hops: &mut HashMap<IpAddr, u8>, src: IpAddr, ttl: u8) {
+  trace!("SYN-ACK from {src} with ttl: {ttl}");
+  if hops.len() >= MAX_CACHED_HOSTS && !hops.contains_key(&src) { hops.clear(); }
+  hops.insert(src, hops_from_ttl(ttl));
+}
+
+fn syn_ack_socket(domain: Domain, filter: &[libc::sock_filter]) -> io::Result<Socket> {
+  let sock = Socket::new(domain, Type::RAW, Some(Protocol::TCP))?;
+  let prog = libc::sock_fprog{ len: filter.len() as u16, filter: filter.as_ptr() as *mut _ };
+  let ret = unsafe {
+    libc::setsockopt(sock.as_raw_fd(), libc::SOL_SOCKET, libc::SO_ATTACH_FILTER, &prog as *const _ as _, size_of::<libc::sock_fprog>() as _)
+  };
+  if ret < 0 { return Err(io::Error::last_os_error()); }
+  sock.set_nonblocking(true)?;
+  Ok(sock)
+}
+
+fn set_int_opt(fd: RawFd, level: libc::c_int, opt: libc::c_int, val: libc::c_int) -> io::Result<()> {
+  let ret = unsafe { libc::setsockopt(fd, level, opt, &val as *const libc::c_int as _, size_of::<libc::c_int>() as _) };
+  if ret < 0 { return Err(io::Error::last_os_error()); }
+  Ok(())
+}
+
+/// Hop count from received TTL, assuming server used one of common initial TTLs
+fn hops_from_ttl(ttl: u8) -> u8 {
+  let initial = [64u8, 128, 255].into_iter().find(|&initial| ttl <= initial).unwrap();
+  initial - ttl
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn hops_from_common_ttls() {
+    assert_eq!(hops_from_ttl(64), 0);
+    assert_eq!(hops_from_ttl(54), 10);
+    assert_eq!(hops_from_ttl(115), 13);
+    assert_eq!(hops_from_ttl(240), 15);
+  }
+
+  #[test]
+  fn shared_sockets_and_loopback_hops() {
+    let first = AutoTtl::shared(3).unwrap();
+    let second = AutoTtl::shared(40).unwrap();
+    assert!(Arc::ptr_eq(&first, &AutoTtl::shared(3).unwrap()));
+    assert!(!Arc::ptr_eq(&first, &second));
+    assert!(Arc::ptr_eq(&first.syn_acks, &second.syn_acks));
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    // SYN-ACK on loopback has TTL 64, so the peer is 0 hops away and the least TTL is used
+    assert_eq!(first.fake_ttl(stream.as_raw_fd()), Some(1));
+  }
+}
@@ -0,0 +1,120 @@
+use std::cell::Cell;
+use std::io;
+use std::ops::Deref;
+
+use log::{debug, warn};
+use tokio_uring::buf::fixed::{FixedBuf, FixedBufPool};
+use tokio_uring::buf::BoundedBuf;
+use tokio_uring::net::TcpStream;
+
+pub const BUF_POOL_SIZE: usize = 256;
+
+/// Buffers registered in io_uring of one worker thread and shared by its connections.
+/// Buffer is taken only for the time of read and write, so idle connections don't hold memory.
+/// If all buffers are in use, temporary heap buffer is allocated and counted as exhaustion
+pub struct BufPool {
+  /// None if pool size is 0 or buffers can't be registered
+  pool: Option<FixedBufPool<Vec<u8>>>,
+  buf_size: usize,
+  reads: Cell<u64>,
+  exhausted: Cell<u64>
+}
+
+pub enum PooledBuf {
+  Fixed(FixedBuf),
+  Heap(Vec<u8>)
+}
+
+impl Deref for PooledBuf {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    match self {
+      Self::Fixed(buf) => buf,
+      Self::Heap(buf) => buf
+    }
+  }
+}
+
+impl BufPool {
+  /// Must be called inside tokio_uring runtime
+  pub fn new(size: usize, buf_size: usize) -> Self {
+    let pool = (size > 0).then(|| FixedBufPool::new((0..size).map(|_| Vec::with_capacity(buf_size))))
+      .and_then(|pool| match pool.register() {
+        Ok(()) => Some(pool),
+        Err(e) => {
+          // registered buffers are limited by RLIMIT_MEMLOCK without CAP_IPC_LOCK
+          warn!("can't register {size} buffers of {buf_size} bytes, heap buffers are used: {e}");
+          None
+        }
+      });
+    Self{ pool, buf_size, reads: Cell::new(0), exhausted: Cell::new(0) }
+  }
+
+  /// Reads from stream which has data, so buffer isn't held while waiting. Returns number of read bytes
+  pub async fn read(&self, stream: &TcpStream) -> io::Result<(usize, PooledBuf)> {
+    self.reads.set(self.reads.get() + 1);
+    match self.pool.as_ref().and_then(|pool| pool.try_next(self.buf_size)) {
+      Some(buf) => {
+        let (res, buf) = stream.read_fixed(buf).await;
+        Ok((res?, PooledBuf::Fixed(buf)))
+      }
+      None => {
+        if self.pool.is_some() { self.on_exhausted(); }
+        let (res, buf) = stream.read(Vec::with_capacity(self.buf_size)).await;
+        Ok((res?, PooledBuf::Heap(buf)))
+      }
+    }
+  }
+
+  pub async fn write_all(stream: &TcpStream, buf: PooledBuf, size: usize) -> io::Result<()> {
+    match buf {
+      PooledBuf::Fixed(buf) => stream.write_fixed_all(buf.slice(..size)).await.0,
+      PooledBuf::Heap(buf) => stream.write_all(buf.slice(..size)).await.0
+    }
+  }
+
+  /// Number of reads and how many of them used a heap buffer because the pool was exhausted
+  pub fn stats(&self) -> (u64, u64) {
+    (self.reads.get(), self.exhausted.get())
+  }
+
+  fn on_exhausted(&self) {
+    let exhausted = self.exhausted.get() + 1;
+    self.exhausted.set(exhausted);
+    debug!("buffer pool is exhausted, heap buffer is used");
+    if exhausted.is_power_of_two() {
+      warn!("buffer pool was exhausted {exhausted} times of {} reads, increase --buf-pool if it happens often", self.reads.get());
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Write;
+  use std::net::TcpListener;
+
+  use super::*;
+
+  #[test]
+  fn fallback_to_heap_when_exhausted() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio_uring::start(async {
+      let pool = BufPool::new(1, 64);
+      let stream = TcpStream::connect(addr).await.unwrap();
+      let (mut peer, _) = listener.accept().unwrap();
+      peer.write_all(b"first").unwrap();
+      let (n, first) = pool.read(&stream).await.unwrap();
+      assert_eq!(&first[..n], b"first");
+      peer.write_all(b"second").unwrap();
+      let (n, second) = pool.read(&stream).await.unwrap();
+      assert_eq!(&second[..n], b"second");
+      // registration may be forbidden by memlock limit, then every buffer is on heap
+      if pool.pool.is_some() {
+        assert!(matches!((first, second), (PooledBuf::Fixed(_), PooledBuf::Heap(_))));
+        assert_eq!(pool.stats(), (2, 1));
+      }
+    });
+  }
+}
@@ -1,16 +1,24 @@
 use std::{io, os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd}, rc::Rc};
 use std::time::Duration;
 use std::ffi::CString;
+use std::fmt;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::Arc;
 
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 use tokio_uring::net::TcpStream;
 use tokio_uring::buf::BoundedBuf;
 use socket2::{self, Socket};
 use log::{trace, debug};
 
-const DEFAULT_TTL: u32 = 64;
+use crate::auto_ttl::AutoTtl;
+use crate::fooling::{send_raw_fake, set_md5sig, Fooling};
+use crate::http::{host_range, is_http_request, mangle_host, HttpMod};
+use crate::tls::{chello_with_sni, split_record, ClientHello, TLS_RECORD_HEADER_LEN};
 
-static FAKE_TLS: [u8; 517] = [
+pub static FAKE_TLS: [u8; 517] = [
   22, 3, 1, 2, 0, 1, 0, 1, 252, 3, 3, 3, 95, 111, 44, 237, 19, 34, 248, 220, 178, 242, 96, 72, 45, 114, 102, 111, 87,
   221, 19, 157, 27, 55, 220, 250, 54, 46, 186, 249, 146, 153, 58, 32, 249, 223, 12, 46, 138, 85, 137, 130, 49, 99, 26,
   239, 168, 190, 8, 88, 167, 163, 90, 24, 211, 150, 95, 4, 92, 180, 98, 175, 137, 215, 15, 139, 0, 62, 19, 2, 19, 3, 19,
@@ -31,18 +39,141 @@ static FAKE_TLS: [u8; 517] = [
 
 
 #[derive(Clone, Debug)]
-#[allow(dead_code)]
 pub enum DesyncType {
   Split,
   Disorder,
   Splitoob,
   Disoob,
-  Fake
+  Fake,
+  /// Split TLS record into two records. It is applied before other types
+  Tlsrec
+}
+
+/// What split position is counted from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PositionBase {
+  /// From start of buffer, or from its end if position is negative
+  Abs,
+  /// From start of host name in TLS SNI or http Host header
+  HostStart,
+  /// From middle of host name
+  HostMid,
+  /// From end of host name
+  HostEnd
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Position {
+  pub offset: i32,
+  pub base: PositionBase
+}
+
+impl Position {
+  /// Returns position in buf or None if it is outside of buf or there isn't host for relative position
+  pub fn resolve(&self, size: usize, host: Option<&Range<usize>>) -> Option<usize> {
+    let base = match self.base {
+      PositionBase::Abs if self.offset < 0 => size as i64,
+      PositionBase::Abs => 0,
+      base => {
+        let host = host?;
+        if host.is_empty() { return None; }
+        let base = match base {
+          PositionBase::HostStart => host.start,
+          PositionBase::HostMid => host.start + host.len() / 2,
+          _ => host.end
+        };
+        base as i64
+      }
+    };
+    let pos = base + self.offset as i64;
+    (pos >= 0 && pos <= size as i64).then_some(pos as usize)
+  }
+}
+
+impl FromStr for Position {
+  type Err = anyhow::Error;
+
+  /// Absolute position: 2, -1. Relative to host name: s+1 (start), m (middle), e-2 (end)
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let base = match s.chars().next() {
+      Some('s') => PositionBase::HostStart,
+      Some('m') => PositionBase::HostMid,
+      Some('e') => PositionBase::HostEnd,
+      _ => {
+        let offset = s.parse().map_err(|_| anyhow!("wrong position: {s}, expected number or position relative to host like s+1, m, e-2"))?;
+        return Ok(Position{ offset, base: PositionBase::Abs });
+      }
+    };
+    let offset = match &s[1..] {
+      "" => 0,
+      offset if offset.starts_with('+') || offset.starts_with('-') => offset.parse()?,
+      offset => bail!("wrong offset from host: {offset}, expected something like s+1, m, e-2")
+    };
+    Ok(Position{ offset, base })
+  }
+}
+
+/// Data sent in fake packets
+#[derive(Clone, Default)]
+pub enum FakePayload {
+  /// ClientHello for www.wikipedia.org
+  #[default]
+  Default,
+  /// Loaded from file or generated ClientHello
+  Data(Arc<Vec<u8>>),
+  /// New random bytes for every fake packet
+  Random
+}
+
+impl FakePayload {
+  /// Returns exactly len bytes of fake payload, padded with zeros if payload is shorter
+  pub fn bytes(&self, len: usize) -> Vec<u8> {
+    let mut fake = match self {
+      Self::Default => FAKE_TLS[..len.min(FAKE_TLS.len())].to_vec(),
+      Self::Data(data) => data[..len.min(data.len())].to_vec(),
+      Self::Random => {
+        let mut fake = vec![0u8; len];
+        unsafe { libc::getrandom(fake.as_mut_ptr() as _, len, 0) };
+        fake
+      }
+    };
+    fake.resize(len, 0);
+    fake
+  }
+}
+
+impl fmt::Debug for FakePayload {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Default => write!(f, "Default"),
+      Self::Data(data) => write!(f, "Data([u8; {}])", data.len()),
+      Self::Random => write!(f, "Random")
+    }
+  }
+}
+
+impl FromStr for FakePayload {
+  type Err = anyhow::Error;
+
+  /// Formats: default, random, sni:<host>, file:<path>
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Ok(match s.split_once(':') {
+      None if s == "default" => Self::Default,
+      None if s == "random" => Self::Random,
+      Some(("sni", host)) => Self::Data(Arc::new(chello_with_sni(&FAKE_TLS, host)?)),
+      Some(("file", path)) => {
+        let data = std::fs::read(path).map_err(|e| anyhow!("cannot read fake payload from {path}: {e}"))?;
+        if data.is_empty() { bail!("fake payload file {path} is empty"); }
+        Self::Data(Arc::new(data))
+      }
+      _ => bail!("wrong fake payload: {s}, expected one of: default, random, sni:<host>, file:<path>")
+    })
+  }
 }
 
 #[derive(Clone, Debug)]
 pub struct SplitPosition {
-  pub pos: i32,
+  pub pos: Position,
   pub desync_type: DesyncType
 }
 
@@ -51,70 +182,118 @@ pub type SplitPositions = Vec<SplitPosition>;
 #[derive(Clone, Debug)]
 pub struct BypassOptions {
   split_positions: SplitPositions,
+  pub http_mods: Vec<HttpMod>,
+  pub fake_payload: FakePayload,
+  /// Payload of fake datagrams of socks5 udp associate, None is FAKE_UDP_PKT
+  pub udp_fake_payload: Option<FakePayload>,
   pub fake_ttl: u32,
+  pub auto_ttl: Option<Arc<AutoTtl>>,
+  pub fooling: Fooling,
   pub oob_data: u8,
   pub timeout: Option<Duration>,
 }
 
 impl BypassOptions {
   pub fn new() -> Self {
-    Self{split_positions: Vec::new(), fake_ttl: 6, oob_data: 97, timeout: None}
+    Self{split_positions: Vec::new(), http_mods: Vec::new(), fake_payload: FakePayload::Default, udp_fake_payload: None, fake_ttl: 6, auto_ttl: None, fooling: Fooling::Ttl, oob_data: 97, timeout: None}
   }
 
-  pub async fn desync(&self, fd: RawFd, stream: Rc<TcpStream>, mut buf: Vec<u8>, size: usize) -> Result<Vec<u8>, anyhow::Error> {
-    let mut prev_pos: i32 = 0;
-    let mut current_pos: usize = 0;
-    for i in 0..self.split_positions.len() {
-      if self.split_positions[i].pos < 0 { current_pos = size - (self.split_positions[i].pos.unsigned_abs() as usize); }
-      else { current_pos = self.split_positions[i].pos as usize; }
-      if prev_pos < 0 { prev_pos += size as i32 }
+  /// Resolves positions for current buf and sorts them
+  fn resolve_positions(&self, buf: &[u8]) -> Vec<(usize, &DesyncType)> {
+    let host = self.split_positions.iter()
+      .any(|p| p.pos.base != PositionBase::Abs)
+      .then(|| match ClientHello::parse(buf) {
+        Some(chello) => chello.sni.map(|sni| sni.range),
+        None => host_range(buf)
+      })
+      .flatten();
+    let mut positions: Vec<_> = self.split_positions.iter()
+      .filter_map(|p| p.pos.resolve(buf.len(), host.as_ref()).map(|pos| (pos, &p.desync_type)))
+      .collect();
+    positions.sort_by_key(|(pos, _)| *pos);
+    positions
+  }
+
+  pub async fn desync(&self, fd: RawFd, stream: Rc<TcpStream>, mut buf: Vec<u8>, mut size: usize) -> Result<Vec<u8>, anyhow::Error> {
+    let mut prev_pos: usize = 0;
+    if !self.http_mods.is_empty() && is_http_request(&buf[..size]) {
+      size = mangle_host(&mut buf, size, &self.http_mods);
+    }
+    let (tlsrec_positions, mut positions): (Vec<_>, Vec<_>) = self.resolve_positions(&buf[..size])
+      .into_iter()
+      .partition(|(_, desync_type)| matches!(desync_type, DesyncType::Tlsrec));
+    // tlsrec positions are dropped for requests which aren't ClientHello, e.g. plain http
+    if !tlsrec_positions.is_empty() && ClientHello::parse(&buf[..size]).is_some() {
+      let tlsrec_positions: Vec<usize> = tlsrec_positions.into_iter().map(|(pos, _)| pos).collect();
+      let applied;
+      (size, applied) = split_record(&mut buf, size, &tlsrec_positions);
+      debug!("tls record was split at {applied:?}");
+      // positions were resolved against original buffer, each new record header shifts them
+      positions.iter_mut().for_each(|(pos, _)| *pos += TLS_RECORD_HEADER_LEN * applied.iter().filter(|&&p| p < *pos).count());
+    }
+    let with_fake_ttl = self.fooling == Fooling::Ttl && positions.iter().any(|(_, desync_type)| matches!(desync_type, DesyncType::Fake));
+    let fake_ttl = match &self.auto_ttl {
+      Some(auto_ttl) if with_fake_ttl => auto_ttl.fake_ttl(fd).unwrap_or(self.fake_ttl),
+      _ => self.fake_ttl
+    };
+    // restore the system TTL instead of a fixed one to not differ from other connections
+    let orig_ttl = BypassOptions::get_ttl(fd)?;
+    for (current_pos, desync_type) in positions {
       debug!("prev_pos = {prev_pos}, current_pos = {current_pos}");
-      if current_pos >= size {
-        let (res, slice) = stream.write(buf.slice(prev_pos as usize..size)).submit().await; res?;
-        buf = slice.into_inner();
-        return Ok(buf);
-      }
-      match self.split_positions[i].desync_type {
+      if current_pos <= prev_pos { continue; }
+      if current_pos >= size { break; }
+      match desync_type {
         DesyncType::Split => {
-          let (res, slice) = stream.write(buf.slice(prev_pos as usize..current_pos)).submit().await; res?;
+          let (res, slice) = stream.write(buf.slice(prev_pos..current_pos)).submit().await; res?;
           buf = slice.into_inner();
-          prev_pos = current_pos as i32;
         }
         DesyncType::Disorder => {
           BypassOptions::set_ttl(fd, 1)?;
-          let (res, slice) = stream.write(buf.slice(prev_pos as usize..current_pos)).submit().await; res?;
+          let (res, slice) = stream.write(buf.slice(prev_pos..current_pos)).submit().await; res?;
           buf = slice.into_inner();
-          BypassOptions::set_ttl(fd, DEFAULT_TTL)?;
-          prev_pos = current_pos as i32;
+          BypassOptions::set_ttl(fd, orig_ttl)?;
         }
         DesyncType::Splitoob => {
-          self.write_oob(stream.as_raw_fd(), &buf[prev_pos as usize..current_pos])?;
-          prev_pos = current_pos as i32;
+          self.write_oob(stream.as_raw_fd(), &buf[prev_pos..current_pos])?;
         }
-        DesyncType::Fake => {
-          BypassOptions::set_ttl(fd, self.fake_ttl)?;
-          BypassOptions::send_fake(fd, current_pos - (prev_pos as usize), Vec::from(&buf[prev_pos as usize..current_pos]))?;
-          BypassOptions::set_ttl(fd, DEFAULT_TTL)?;
-          prev_pos = current_pos as i32;
+        DesyncType::Disoob => {
+          BypassOptions::set_ttl(fd, 1)?;
+          self.write_oob(stream.as_raw_fd(), &buf[prev_pos..current_pos])?;
+          BypassOptions::set_ttl(fd, orig_ttl)?;
         }
-        _ => bail!("unimplemented type")
+        DesyncType::Fake => match self.fooling {
+          Fooling::Ttl => {
+            BypassOptions::set_ttl(fd, fake_ttl)?;
+            self.send_fake(fd, current_pos - prev_pos, Vec::from(&buf[prev_pos..current_pos]))?;
+            BypassOptions::set_ttl(fd, orig_ttl)?;
+          }
+          Fooling::Md5sig => {
+            set_md5sig(fd, true)?;
+            let sent = self.send_fake(fd, current_pos - prev_pos, Vec::from(&buf[prev_pos..current_pos]));
+            set_md5sig(fd, false)?;
+            sent?;
+          }
+          Fooling::Badseq | Fooling::Badsum => {
+            send_raw_fake(fd, &self.fake_payload.bytes(current_pos - prev_pos), self.fooling)?;
+            let (res, slice) = stream.write(buf.slice(prev_pos..current_pos)).submit().await; res?;
+            buf = slice.into_inner();
+          }
+        },
+        DesyncType::Tlsrec => unreachable!("tlsrec positions are applied before tcp desync")
       }
+      prev_pos = current_pos;
     }
-    if current_pos != size {
-      let (res, slice) = stream.write(buf.slice(current_pos..size)).submit().await; res?;
+    if prev_pos != size {
+      let (res, slice) = stream.write(buf.slice(prev_pos..size)).submit().await; res?;
       buf = slice.into_inner();
     }
     Ok(buf)
   }
 
-  pub fn at_least_one_option(&self) -> bool { !self.split_positions.is_empty()}
+  pub fn at_least_one_option(&self) -> bool { !self.split_positions.is_empty() || !self.http_mods.is_empty() }
 
   pub fn append_options(&mut self, mut options: SplitPositions) {
     self.split_positions.append(options.as_mut());
-    self.split_positions.sort_by(|a,b| {
-      if (a.pos < 0 || b.pos < 0) && !(a.pos < 0 && b.pos < 0) { return a.pos.cmp(&b.pos).reverse(); }
-      a.pos.cmp(&b.pos)
-    });
   }
 
   pub fn write_oob(&self, fd: RawFd, buf: &[u8]) -> io::Result<usize> {
@@ -126,29 +305,227 @@ impl BypassOptions {
     ret
   }
 
-  pub fn send_fake(fd: RawFd, current_pos: usize, buf: Vec<u8>) -> Result<(), anyhow::Error> {
+  pub fn send_fake(&self, fd: RawFd, current_pos: usize, buf: Vec<u8>) -> Result<(), anyhow::Error> {
     let mut w_bytes;
     debug!("fake current_pos = {current_pos}");
+    let fake = self.fake_payload.bytes(current_pos);
     let name = CString::new("name").unwrap();
     let ffd = unsafe { libc::memfd_create(name.as_ptr(), 0) };
     if ffd < 0 { let _ = fd.into_raw_fd(); bail!("ffd < 0"); }
     unsafe {
-      w_bytes = libc::write(ffd, FAKE_TLS.as_ptr() as _, current_pos);
+      w_bytes = libc::write(ffd, fake.as_ptr() as _, current_pos);
       trace!("fake bytes write: {w_bytes}", );
       libc::lseek(ffd, 0, libc::SEEK_SET);
-      if libc::sendfile(fd, ffd, 0 as _, current_pos) < 0 { let _ = fd.into_raw_fd(); bail!("sendfile < 0"); }
+      if libc::sendfile(fd, ffd, 0 as _, current_pos) < 0 { libc::close(ffd); bail!("sendfile < 0"); }
       libc::lseek(ffd, 0, libc::SEEK_SET);
       w_bytes = libc::write(ffd, buf.as_ptr() as _, current_pos);
       trace!("good bytes write: {w_bytes}");
-      let _ = fd.into_raw_fd();
+      libc::close(ffd);
     }
     Ok(())
   }
 
+  /// Sets TTL of IPv4 socket or hop limit of IPv6 socket
   pub fn set_ttl(fd: RawFd, ttl: u32) -> io::Result<()> {
     let sock = unsafe { Socket::from_raw_fd(fd) };
-    let ret = sock.set_ttl(ttl);
+    let ret = match BypassOptions::is_ipv6(&sock) {
+      Ok(true) => sock.set_unicast_hops_v6(ttl),
+      Ok(false) => sock.set_ttl(ttl),
+      Err(e) => Err(e)
+    };
+    let _ = sock.into_raw_fd();
+    ret
+  }
+
+  /// Returns TTL of IPv4 socket or hop limit of IPv6 socket
+  pub fn get_ttl(fd: RawFd) -> io::Result<u32> {
+    let sock = unsafe { Socket::from_raw_fd(fd) };
+    let ret = match BypassOptions::is_ipv6(&sock) {
+      Ok(true) => sock.unicast_hops_v6(),
+      Ok(false) => sock.ttl(),
+      Err(e) => Err(e)
+    };
     let _ = sock.into_raw_fd();
     ret
   }
+
+  /// IPv4-mapped addresses of IPv6 sockets use IPv4 TTL
+  fn is_ipv6(sock: &Socket) -> io::Result<bool> {
+    Ok(matches!(sock.local_addr()?.as_socket(), Some(SocketAddr::V6(addr)) if addr.ip().to_ipv4_mapped().is_none()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Read;
+  use std::net::TcpListener;
+  use std::thread;
+
+  use super::*;
+
+  fn desync_over_loopback(positions: SplitPositions, payload: &[u8]) -> Vec<u8> {
+    desync_over_loopback_with(positions, payload, Fooling::Ttl)
+  }
+
+  fn desync_over_loopback_with(positions: SplitPositions, payload: &[u8], fooling: Fooling) -> Vec<u8> {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+      let (mut stream, _) = listener.accept().unwrap();
+      let mut received = Vec::new();
+      stream.read_to_end(&mut received).unwrap();
+      received
+    });
+    let mut options = BypassOptions::new();
+    options.append_options(positions);
+    options.fooling = fooling;
+    let payload = payload.to_vec();
+    tokio_uring::start(async move {
+      let stream = Rc::new(TcpStream::connect(addr).await.unwrap());
+      let size = payload.len();
+      options.desync(stream.as_raw_fd(), stream.clone(), payload, size).await.unwrap();
+      stream.shutdown(std::net::Shutdown::Write).unwrap();
+    });
+    server.join().unwrap()
+  }
+
+  #[test]
+  fn split_delivers_whole_payload() {
+    let payload = b"\x16\x03\x01\x00\x10hello, split world";
+    let received = desync_over_loopback(vec![
+      SplitPosition{ pos: "2".parse().unwrap(), desync_type: DesyncType::Split },
+      SplitPosition{ pos: "-3".parse().unwrap(), desync_type: DesyncType::Split },
+    ], payload);
+    assert_eq!(received, payload);
+  }
+
+  #[test]
+  fn split_around_sni() {
+    let received = desync_over_loopback(vec![
+      SplitPosition{ pos: "s+1".parse().unwrap(), desync_type: DesyncType::Split },
+      SplitPosition{ pos: "m".parse().unwrap(), desync_type: DesyncType::Disoob },
+      SplitPosition{ pos: "e-1".parse().unwrap(), desync_type: DesyncType::Split },
+    ], &FAKE_TLS);
+    assert_eq!(received, FAKE_TLS);
+  }
+
+  #[test]
+  fn fake_with_md5sig() {
+    let received = desync_over_loopback_with(vec![
+      SplitPosition{ pos: "s+1".parse().unwrap(), desync_type: DesyncType::Fake },
+    ], &FAKE_TLS, Fooling::Md5sig);
+    assert_eq!(received, FAKE_TLS);
+  }
+
+  #[test]
+  fn disorder_restores_original_ttl() {
+    // hop limit is used instead of ttl for IPv6
+    for addr in ["127.0.0.1:0", "[::1]:0"] {
+      let listener = TcpListener::bind(addr).unwrap();
+      let addr = listener.local_addr().unwrap();
+      let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        received
+      });
+      let mut options = BypassOptions::new();
+      options.append_options(vec![SplitPosition{ pos: "2".parse().unwrap(), desync_type: DesyncType::Disorder }]);
+      tokio_uring::start(async move {
+        let stream = Rc::new(TcpStream::connect(addr).await.unwrap());
+        let fd = stream.as_raw_fd();
+        BypassOptions::set_ttl(fd, 77).unwrap();
+        options.desync(fd, stream.clone(), b"hello".to_vec(), 5).await.unwrap();
+        assert_eq!(BypassOptions::get_ttl(fd).unwrap(), 77);
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+      });
+      assert_eq!(server.join().unwrap(), b"hello");
+    }
+  }
+
+  #[test]
+  fn parse_positions() {
+    assert_eq!("-1".parse::<Position>().unwrap(), Position{ offset: -1, base: PositionBase::Abs });
+    assert_eq!("s+1".parse::<Position>().unwrap(), Position{ offset: 1, base: PositionBase::HostStart });
+    assert_eq!("m".parse::<Position>().unwrap(), Position{ offset: 0, base: PositionBase::HostMid });
+    assert_eq!("e-2".parse::<Position>().unwrap(), Position{ offset: -2, base: PositionBase::HostEnd });
+    assert!("s1".parse::<Position>().is_err());
+    assert!("x+1".parse::<Position>().is_err());
+  }
+
+  #[test]
+  fn resolve_sni_positions() {
+    let sni = ClientHello::parse(&FAKE_TLS).unwrap().sni.unwrap().range;
+    let resolve = |pos: &str| pos.parse::<Position>().unwrap().resolve(FAKE_TLS.len(), Some(&sni));
+    assert_eq!(resolve("s+1"), Some(sni.start + 1));
+    assert_eq!(resolve("m"), Some(sni.start + sni.len() / 2));
+    assert_eq!(resolve("e-1"), Some(sni.end - 1));
+    assert_eq!(resolve("-1"), Some(FAKE_TLS.len() - 1));
+    assert_eq!(resolve("-1000"), None);
+    assert_eq!("s".parse::<Position>().unwrap().resolve(10, None), None);
+  }
+
+  #[test]
+  fn tlsrec_with_split() {
+    let received = desync_over_loopback(vec![
+      SplitPosition{ pos: "s+2".parse().unwrap(), desync_type: DesyncType::Tlsrec },
+      SplitPosition{ pos: "s+2".parse().unwrap(), desync_type: DesyncType::Split },
+      SplitPosition{ pos: "-1".parse().unwrap(), desync_type: DesyncType::Split },
+    ], &FAKE_TLS);
+    let sni_start = ClientHello::parse(&FAKE_TLS).unwrap().sni.unwrap().range.start;
+    assert_eq!(received.len(), FAKE_TLS.len() + TLS_RECORD_HEADER_LEN);
+    assert_eq!(received[TLS_RECORD_HEADER_LEN..sni_start + 2], FAKE_TLS[TLS_RECORD_HEADER_LEN..sni_start + 2]);
+    assert_eq!(received[sni_start + 2 + TLS_RECORD_HEADER_LEN..], FAKE_TLS[sni_start + 2..]);
+  }
+
+  #[test]
+  fn tlsrec_skips_http_request() {
+    let payload = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+    let received = desync_over_loopback(vec![
+      SplitPosition{ pos: "s+2".parse().unwrap(), desync_type: DesyncType::Tlsrec },
+      SplitPosition{ pos: "2".parse().unwrap(), desync_type: DesyncType::Tlsrec },
+      SplitPosition{ pos: "s+1".parse().unwrap(), desync_type: DesyncType::Split },
+    ], payload);
+    assert_eq!(received, payload);
+  }
+
+  #[test]
+  fn split_http_host() {
+    let payload = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+    let received = desync_over_loopback(vec![
+      SplitPosition{ pos: "s+1".parse().unwrap(), desync_type: DesyncType::Split },
+      SplitPosition{ pos: "e".parse().unwrap(), desync_type: DesyncType::Disorder },
+    ], payload);
+    assert_eq!(received, payload);
+  }
+
+  #[test]
+  fn fake_payload_len() {
+    assert_eq!(FakePayload::Default.bytes(10), FAKE_TLS[..10]);
+    assert_eq!(FakePayload::Default.bytes(600)[FAKE_TLS.len()..], [0u8; 600 - FAKE_TLS.len()]);
+    assert_eq!(FakePayload::Random.bytes(100).len(), 100);
+    let FakePayload::Data(data) = "sni:example.com".parse::<FakePayload>().unwrap() else { panic!("expected data") };
+    assert_eq!(ClientHello::parse(&data).unwrap().sni.unwrap().host, "example.com");
+    assert!("file:/nonexistent".parse::<FakePayload>().is_err());
+    assert!("sni".parse::<FakePayload>().is_err());
+  }
+
+  #[test]
+  fn disoob_strips_oob_byte() {
+    let payload = b"\x16\x03\x01\x00\x10hello, disoob world";
+    let received = desync_over_loopback(vec![
+      SplitPosition{ pos: "3".parse().unwrap(), desync_type: DesyncType::Disoob },
+    ], payload);
+    assert_eq!(received, payload);
+  }
+
+  #[test]
+  fn disoob_with_negative_position() {
+    let payload = b"\x16\x03\x01\x00\x10hello, disoob world";
+    let received = desync_over_loopback(vec![
+      SplitPosition{ pos: "1".parse().unwrap(), desync_type: DesyncType::Split },
+      SplitPosition{ pos: "-5".parse().unwrap(), desync_type: DesyncType::Disoob },
+    ], payload);
+    assert_eq!(received, payload);
+  }
 }
@@ -1,4 +1,6 @@
 #include <arpa/inet.h>
+#include <errno.h>
+#include <poll.h>
 #include <stdint.h>
 #include <stdio.h>
 #include <stdlib.h>
@@ -6,6 +8,7 @@
 #include <sys/socket.h>
 #include <netinet/in.h>
 #include <netinet/ip.h>
+#include <netinet/ip6.h>
 #include <netinet/udp.h>
 #include <unistd.h>
 
@@ -13,6 +16,7 @@
 #include <libnetfilter_queue/libnetfilter_queue.h>
 #include <libnetfilter_queue/libnetfilter_queue_udp.h>
 #include <libnetfilter_queue/libnetfilter_queue_ipv4.h>
+#include <libnetfilter_queue/libnetfilter_queue_ipv6.h>
 
 #include "c-udp-bypass.h"
 
@@ -65,30 +69,92 @@ int send_udp_packet(uint32_t src_ip, uint32_t dst_ip, uint16_t src_port, uint16_
   return 0;
 }
 
+int send_udp6_packet(struct in6_addr *src_ip, struct in6_addr *dst_ip, uint16_t src_port, uint16_t dst_port, struct bypass_data *b_data) {
+  int fakefd;
+  uint8_t buffer[sizeof(struct ip6_hdr) + sizeof(struct udphdr) + b_data->fake_pkt_payload_len];
+  struct ip6_hdr *ip6h = (struct ip6_hdr *)buffer;
+  struct udphdr *udph = (struct udphdr *)(buffer + sizeof(struct ip6_hdr));
+  uint8_t *payload = buffer + sizeof(struct ip6_hdr) + sizeof(struct udphdr);
+  struct sockaddr_in6 sin6;
+  // IPPROTO_RAW socket expects packets with IPv6 header
+  if ((fakefd = socket(AF_INET6, SOCK_RAW, IPPROTO_RAW)) < 0) bail("socket");
+  if (setsockopt(fakefd, SOL_SOCKET, SO_MARK, &b_data->mark, sizeof(b_data->mark)) < 0)
+    close_bail(fakefd, "setsockopt SO_MARK");
+
+  ip6h->ip6_flow = htonl(6 << 28);
+  ip6h->ip6_plen = htons(sizeof(struct udphdr) + b_data->fake_pkt_payload_len);
+  ip6h->ip6_nxt = IPPROTO_UDP;
+  ip6h->ip6_hlim = b_data->fake_ttl;
+  ip6h->ip6_src = *src_ip;
+  ip6h->ip6_dst = *dst_ip;
+
+  udph->source = src_port;
+  udph->dest = dst_port;
+  udph->len = ip6h->ip6_plen;
+  udph->check = 0;
+  memcpy(payload, b_data->fake_pkt_payload, b_data->fake_pkt_payload_len);
+  nfq_udp_compute_checksum_ipv6(udph, ip6h);
+
+  memset(&sin6, 0, sizeof(sin6));
+  sin6.sin6_family = AF_INET6;
+  sin6.sin6_addr = ip6h->ip6_dst;
+
+  if (sendto(fakefd, buffer, sizeof(buffer), 0, (struct sockaddr *)&sin6, sizeof(sin6)) < 0) close_bail(fakefd, "sendto");
+  close(fakefd);
+  return 0;
+}
+
+static void handle_ipv4(unsigned char *packetData, int len, struct bypass_data *cb_data) {
+  struct iphdr *ip = (struct iphdr *)packetData;
+  if (ip->protocol != IPPROTO_UDP) {
+    fprintf(stderr, log_msg"Error: it isn't udp packet, maybe there is not iptables rule?\n");
+    return;
+  }
+  // header len comes from the packet, udp header must fit after ip header with options
+  if (ip->ihl < 5 || ip->ihl * 4 + sizeof(struct udphdr) > (size_t)len) {
+    fprintf(stderr, log_msg"Error: truncated udp packet with ihl: %d, len: %d\n", ip->ihl, len);
+    return;
+  }
+  struct udphdr *udp = (struct udphdr *)(packetData + (ip->ihl * 4));
+  if (send_udp_packet(ip->saddr, ip->daddr, udp->source, udp->dest, cb_data) != 0)
+    fprintf(stderr, log_msg"Failed to send UDP packet\n");
+  else if (cb_data->log_level == Trace) {
+    struct in_addr src_addr, dst_addr;
+    src_addr.s_addr = ip->saddr;
+    dst_addr.s_addr = ip->daddr;
+    char src_ip_str[INET_ADDRSTRLEN], dst_ip_str[INET_ADDRSTRLEN];
+    inet_ntop(AF_INET, &src_addr, src_ip_str, INET_ADDRSTRLEN);
+    inet_ntop(AF_INET, &dst_addr, dst_ip_str, INET_ADDRSTRLEN);
+    printf(log_msg"Sent 64-byte UDP packet from %s:%d to %s:%d\n", src_ip_str, ntohs(udp->source), dst_ip_str, ntohs(udp->dest));
+  }
+}
+
+static void handle_ipv6(unsigned char *packetData, struct bypass_data *cb_data) {
+  struct ip6_hdr *ip6 = (struct ip6_hdr *)packetData;
+  // packets with extension headers before udp header aren't supported
+  if (ip6->ip6_nxt != IPPROTO_UDP) {
+    fprintf(stderr, log_msg"Error: it isn't udp packet, maybe there is not ip6tables rule?\n");
+    return;
+  }
+  struct udphdr *udp = (struct udphdr *)(packetData + sizeof(struct ip6_hdr));
+  if (send_udp6_packet(&ip6->ip6_src, &ip6->ip6_dst, udp->source, udp->dest, cb_data) != 0)
+    fprintf(stderr, log_msg"Failed to send UDP packet\n");
+  else if (cb_data->log_level == Trace) {
+    char src_ip_str[INET6_ADDRSTRLEN], dst_ip_str[INET6_ADDRSTRLEN];
+    inet_ntop(AF_INET6, &ip6->ip6_src, src_ip_str, INET6_ADDRSTRLEN);
+    inet_ntop(AF_INET6, &ip6->ip6_dst, dst_ip_str, INET6_ADDRSTRLEN);
+    printf(log_msg"Sent 64-byte UDP packet from [%s]:%d to [%s]:%d\n", src_ip_str, ntohs(udp->source), dst_ip_str, ntohs(udp->dest));
+  }
+}
+
 static int cb(struct nfq_q_handle *qh, struct nfgenmsg *nfmsg, struct nfq_data *nfa, void *data) {
   (void)nfmsg;
   unsigned char *packetData;
   struct bypass_data *cb_data = (struct bypass_data*)data;
   int len = nfq_get_payload(nfa, &packetData);
-  if (len >= 0) {
-    struct iphdr *ip = (struct iphdr *)packetData;
-    if (ip->protocol != IPPROTO_UDP)
-      fprintf(stderr, log_msg"Error: it isn't udp packet, maybe there is not iptables rule?\n");
-    else {
-      struct udphdr *udp = (struct udphdr *)(packetData + (ip->ihl * 4));
-      if (send_udp_packet(ip->saddr, ip->daddr, udp->source, udp->dest, cb_data) != 0)
-        fprintf(stderr, log_msg"Failed to send UDP packet\n");
-      else if (cb_data->log_level == Trace) {
-        struct in_addr src_addr, dst_addr;
-        src_addr.s_addr = ip->saddr;
-        dst_addr.s_addr = ip->daddr;
-        char src_ip_str[INET_ADDRSTRLEN], dst_ip_str[INET_ADDRSTRLEN];
-        inet_ntop(AF_INET, &src_addr, src_ip_str, INET_ADDRSTRLEN);
-        inet_ntop(AF_INET, &dst_addr, dst_ip_str, INET_ADDRSTRLEN);
-        printf(log_msg"Sent 64-byte UDP packet from %s:%d to %s:%d\n", src_ip_str, ntohs(udp->source), dst_ip_str, ntohs(udp->dest));
-      }
-    }
-  }
+  if (len >= (int)sizeof(struct iphdr) && packetData[0] >> 4 == 4) handle_ipv4(packetData, len, cb_data);
+  else if (len >= (int)(sizeof(struct ip6_hdr) + sizeof(struct udphdr)) && packetData[0] >> 4 == 6) handle_ipv6(packetData, cb_data);
+  else if (len >= 0) fprintf(stderr, log_msg"Error: unknown packet with len: %d\n", len);
   uint32_t id = get_pkt_id(nfa);
   int ret = nfq_set_verdict(qh, id, NF_ACCEPT, 0, NULL);
   if (cb_data->log_level >= Debug) printf(log_msg"Sent original packet with ret: %d, id: %u\n", ret, id);
@@ -97,20 +163,30 @@ static int cb(struct nfq_q_handle *qh, struct nfgenmsg *nfmsg, struct nfq_data *
 
 int init_nfq(struct bypass_data *cb_data, struct nfq_handle **h, struct nfq_q_handle **qh) {
   if (!(*h = nfq_open())) bail("nfq_open");
-  // TODO: add ipv6 support
   if (nfq_unbind_pf(*h, AF_INET) < 0) bail("nfq_unbind_pf");
   if (nfq_bind_pf(*h, AF_INET) < 0) bail("nfq_bind_pf");
+  if (nfq_unbind_pf(*h, AF_INET6) < 0) bail("nfq_unbind_pf AF_INET6");
+  if (nfq_bind_pf(*h, AF_INET6) < 0) bail("nfq_bind_pf AF_INET6");
   if (!(*qh = nfq_create_queue(*h, cb_data->queue_num, &cb, (void*)cb_data))) bail("nfq_create_queue");
   if (nfq_set_mode(*qh, NFQNL_COPY_PACKET, 0xffff) < 0) bail("nfq_set_mode");
   return 0;
 }
 
-void run_nfq(struct nfq_handle *h, char *buf, size_t buf_size) {
+void run_nfq(struct nfq_handle *h, char *buf, size_t buf_size, int stop_fd) {
   int rv;
   int fd = nfq_fd(h);
+  // negative stop_fd is ignored by poll
+  struct pollfd fds[2] = {{ .fd = fd, .events = POLLIN }, { .fd = stop_fd, .events = POLLIN }};
   setsockopt(fd, SOL_NETLINK, NETLINK_NO_ENOBUFS, &yes, sizeof(int));
-  while ((rv = recv(fd, buf, buf_size, 0)) && rv >= 0)
+  for (;;) {
+    if (poll(fds, 2, -1) < 0) {
+      if (errno == EINTR) continue;
+      break;
+    }
+    if (fds[1].revents) break;
+    if ((rv = recv(fd, buf, buf_size, 0)) <= 0) break;
     nfq_handle_packet(h, buf, rv);
+  }
 }
 
 void destroy_nfq(struct nfq_handle *h, struct nfq_q_handle *qh) {
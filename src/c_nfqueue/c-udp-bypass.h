@@ -43,7 +43,7 @@ struct bypass_data {
 };
 
 int init_nfq(struct bypass_data *cb_data, struct nfq_handle **h, struct nfq_q_handle **qh);
-void run_nfq(struct nfq_handle *h, char *buf, size_t buf_size);
+void run_nfq(struct nfq_handle *h, char *buf, size_t buf_size, int stop_fd);
 void destroy_nfq(struct nfq_handle *h, struct nfq_q_handle *qh);
 
 #endif
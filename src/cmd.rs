@@ -1,10 +1,13 @@
-use std::{net::SocketAddr, time::Duration, str::FromStr};
+use std::{net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc};
 
-use anyhow::bail;
-use structopt::StructOpt;
+use anyhow::{anyhow, bail};
+use structopt::{clap::ArgMatches, StructOpt};
 
 use crate::proxy_server::{ProxyServer, BUF_SIZE_STR};
-use crate::bypass::{DesyncType, SplitPosition, SplitPositions};
+use crate::bypass::{FakePayload, Position};
+use crate::config::{Config, Profile, DEFAULT_PROFILE};
+use crate::fooling::Fooling;
+use crate::http::HttpMod;
 
 #[cfg(feature = "udp-desync")]
 use crate::udp::{self, UdpBypassHelpData, UDP_RECV_BUF_SIZE};
@@ -36,7 +39,7 @@ macro_rules! gen_subcommand {
         #[structopt()]
         proxy_addr: String,
 
-        /// TTL for fake packets.
+        /// TTL for fake packets. Also used for fake datagrams in socks5 udp associate.
         ///
         /// If you get something like this when connecting:
         /// Secure Connection Failed
@@ -45,6 +48,28 @@ macro_rules! gen_subcommand {
         #[structopt(short="F", long, default_value="6")]
         fake_ttl: u8,
 
+        /// Auto TTL for fake packets: hops to the server minus this value.
+        /// Hops are estimated from TTL of the server's SYN-ACK, fake-ttl is used if it is unknown. Needs root
+        #[structopt(short="A", long)]
+        auto_ttl: Option<u8>,
+
+        /// Payload of fake packets.
+        /// Can be: default (ClientHello for www.wikipedia.org), random (random bytes),
+        /// sni:<host> (ClientHello with given SNI), file:<path> (data from file)
+        #[structopt(short="P", long, default_value="default")]
+        fake_payload: FakePayload,
+
+        /// Payload of fake datagrams sent before datagrams of socks5 udp associate.
+        /// Can be the same as fake-payload, 64 zero bytes if not set
+        #[structopt(long)]
+        udp_fake_payload: Option<FakePayload>,
+
+        /// How fake packets are made harmless for the server.
+        /// Can be: ttl (fake packets with fake-ttl), md5sig (TCP MD5 signature option),
+        /// badseq (wrong seq and ack, needs root), badsum (wrong TCP checksum, needs root)
+        #[structopt(long, default_value="ttl")]
+        fooling: Fooling,
+
         /// TCP buf size
         #[structopt(default_value=BUF_SIZE_STR, short, long)]
         buf_size: usize,
@@ -54,33 +79,51 @@ macro_rules! gen_subcommand {
         timeout: f32,
 
         /// disorder position
-        #[structopt(short, long, default_value, hide_default_value=true)]
-        disorder: i32,
+        #[structopt(short, long)]
+        disorder: Option<Position>,
 
         /// Split positions.
-        /// Can be single number or list of numbers separated by space: -s 2 -1 10 or many --split arguments: -s 2 -s -1 -s 10
+        /// Can be single position or list of positions separated by space: -s 2 -1 10 or many --split arguments: -s 2 -s -1 -s 10.
+        /// Position can be relative to host name in TLS SNI or http Host header:
+        /// s+1 - start of host + 1, m - middle of host, e-2 - end of host - 2.
+        /// It is the same for all options with positions
         #[structopt(short, long, value_terminator("."))]
-        split: Vec<i32>,
+        split: Vec<Position>,
 
         /// Disorder with oob data positions.
-        /// Can be single number or list of numbers separated by space: -D 2 -1 10 or many --disoob arguments: -D 2 -D -1 -D 10
+        /// Can be single position or list of positions separated by space: -D 2 -1 10 or many --disoob arguments: -D 2 -D -1 -D 10
         #[structopt(long, short="D", value_terminator("."))]
-        disoob: Vec<i32>,
+        disoob: Vec<Position>,
 
         /// Split with oob data positions.
-        /// Can be single number or list of numbers separated by space: -S 2 -1 10 or many --splitoob arguments: -S 2 -S -1 -S 10
+        /// Can be single position or list of positions separated by space: -S 2 -1 10 or many --splitoob arguments: -S 2 -S -1 -S 10
         #[structopt(long, short="S", value_terminator("."))]
-        splitoob: Vec<i32>,
+        splitoob: Vec<Position>,
 
         /// Split with send fake packets.
-        /// Can be single number or list of numbers separated by space: -f 2 -1 10 or many --fake arguments: -f 2 -f -1 -f 10
+        /// Can be single position or list of positions separated by space: -f 2 -1 10 or many --fake arguments: -f 2 -f -1 -f 10
         #[structopt(short, long, value_terminator("."))]
-        fake: Vec<i32>,
+        fake: Vec<Position>,
+
+        /// Split TLS record positions.
+        /// The first TLS record is split into many records at these positions before other desync.
+        /// Can be single position or list of positions separated by space: -r 2 s+1 or many --tlsrec arguments: -r 2 -r s+1
+        #[structopt(short="r", long, value_terminator("."))]
+        tlsrec: Vec<Position>,
+
+        /// Host header modifications for the first http request.
+        /// Can be: host-case (hOsT), domain-case (eXaMpLe.cOm), no-space (Host:example.com), extra-space (Host: \t example.com)
+        #[structopt(short="M", long, value_terminator("."))]
+        mod_http: Vec<HttpMod>,
 
         /// Byte sent outside the main stream
         #[structopt(short, long, default_value="97")]
         oob_data: u8,
 
+        /// Transparent proxy mode.
+        /// Accept connections redirected by iptables/nftables REDIRECT or TPROXY instead of socks/http connect requests
+        #[structopt(short="T", long)]
+        transparent: bool,
 
         $(#[$attr_udp])*
         /// Udp command
@@ -116,6 +159,27 @@ macro_rules! gen_subcommand {
         /// TCP command
         #[structopt(subcommand)]
         tcp: Option<$tcp_name>,
+      },
+      $(#[$attr_tcp])*
+      $(#[$attr_udp])*
+      #[allow(unused)]
+      #[structopt(name = "probe", about = "Find desync strategies which work for given hosts")]
+      /// Find desync strategies which work for given hosts
+      ///
+      /// Tries combinations of desync types, positions and fake TTLs with TLS handshakes to the hosts
+      /// and prints strategies which got the whole server handshake flight as config profiles, the best first
+      Probe {
+        /// Hosts in host or host:port format, default port is 443
+        #[structopt(required = true)]
+        hosts: Vec<String>,
+
+        /// TTLs for fake packets separated by comma
+        #[structopt(short="F", long, use_delimiter = true, default_value="4,6,8")]
+        fake_ttl: Vec<u8>,
+
+        /// Timeout of every handshake in secs
+        #[structopt(short, long, default_value="3")]
+        timeout: f32,
       }
     }
   };
@@ -137,10 +201,41 @@ gen_subcommands!(Subcommands, TcpSubcommand, UdpSubcommand);
 #[allow(dead_code)]
 /// Bypass dpi written in rust inspired by byedpi and zapret.
 ///
-/// Rustpass-dpi supports bypassing tls using socks4 proxy and udp using nfqueue and network namespace(if need)
+/// Rustpass-dpi supports bypassing tls using socks4/socks5 proxy and udp using nfqueue and network namespace(if need)
 pub struct Cmd {
   #[structopt(subcommand)]
-  pub cmd: Subcommands,
+  pub cmd: Option<Subcommands>,
+
+  /// TOML config file with listeners, named desync profiles and udp settings.
+  /// Without tcp subcommand all listeners of config are started
+  #[structopt(short, long)]
+  pub config: Option<PathBuf>,
+
+  /// Config profile used for tcp subcommand, its options override options of profile. Default is "default"
+  #[structopt(short, long)]
+  pub profile: Option<String>,
+
+  /// Auto mode: config profiles separated by comma which are tried one by one
+  /// if the server answers with RST, TLS alert or doesn't answer. The profile which worked is remembered for the host.
+  /// Overrides auto profiles of config listeners
+  #[structopt(short, long, requires = "config", use_delimiter = true)]
+  pub auto: Vec<String>,
+
+  /// Threads with own io_uring and SO_REUSEPORT listener for every listen addr, kernel spreads connections between them.
+  /// Overrides workers of config listeners. Default is 1
+  #[structopt(short, long)]
+  pub workers: Option<usize>,
+
+  /// Buffers registered in io_uring of every worker. Connections take them only while data is copied,
+  /// if all are taken heap buffers are used and exhaustion is logged. 0 disables registration.
+  /// Overrides buf-pool of config listeners. Default is 256
+  #[structopt(long)]
+  pub buf_pool: Option<usize>,
+
+  /// Copy data of connections through buffers instead of splice, it saves 4 pipe fds per connection.
+  /// Overrides splice of config listeners
+  #[structopt(long)]
+  pub no_splice: bool,
 
   /// Experimental. Run app with rustpass-dpi. It makes sense only with --netns option.
   /// To use this option you need to set suid bit.
@@ -149,68 +244,107 @@ pub struct Cmd {
   pub run_app: Option<String>,
 }
 
-impl TryInto<ProxyServer> for Subcommands {
-  type Error = anyhow::Error;
-
-  fn try_into(self) -> Result<ProxyServer, Self::Error> {
-    struct DesyncVecs {
-      disorder: i32,
-      split: Vec<i32>,
-      disoob: Vec<i32>,
-      splitoob: Vec<i32>,
-      fake: Vec<i32>
-    }
+struct DesyncVecs {
+  disorder: Option<Position>,
+  split: Vec<Position>,
+  disoob: Vec<Position>,
+  splitoob: Vec<Position>,
+  fake: Vec<Position>,
+  tlsrec: Vec<Position>
+}
 
-    trait PushPositions {
-      fn push_split_pos(&mut self, desync_vecs: DesyncVecs);
-    }
+/// Options with default values are set only if they were given explicitly, so they don't override config profile
+#[allow(clippy::too_many_arguments)]
+fn tcp_profile(m: &ArgMatches, fake_ttl: u8, auto_ttl: Option<u8>, fake_payload: FakePayload, udp_fake_payload: Option<FakePayload>,
+               fooling: Fooling, buf_size: usize, timeout: f32, oob_data: u8, mod_http: Vec<HttpMod>, desync_vecs: DesyncVecs) -> Profile {
+  let explicit = |name: &str| m.occurrences_of(name) > 0;
+  Profile {
+    fake_ttl: explicit("fake-ttl").then_some(fake_ttl),
+    auto_ttl,
+    fake_payload: explicit("fake-payload").then_some(fake_payload),
+    udp_fake_payload,
+    fooling: explicit("fooling").then_some(fooling),
+    buf_size: explicit("buf-size").then_some(buf_size),
+    timeout: explicit("timeout").then_some(timeout),
+    disorder: desync_vecs.disorder,
+    split: desync_vecs.split,
+    disoob: desync_vecs.disoob,
+    splitoob: desync_vecs.splitoob,
+    fake: desync_vecs.fake,
+    tlsrec: desync_vecs.tlsrec,
+    mod_http,
+    oob_data: explicit("oob-data").then_some(oob_data)
+  }
+}
 
-    impl PushPositions for SplitPositions {
-      #[inline]
-      fn push_split_pos(&mut self, desync_vecs: DesyncVecs) {
-        if desync_vecs.disorder != 0 { self.push(SplitPosition{ pos: desync_vecs.disorder, desync_type: DesyncType::Disorder }) }
-        desync_vecs.split.iter().for_each(|x| self.push(SplitPosition{ pos: *x, desync_type: DesyncType::Split }));
-        desync_vecs.disoob.iter().for_each(|x| self.push(SplitPosition{ pos: *x, desync_type: DesyncType::Disoob }));
-        desync_vecs.splitoob.iter().for_each(|x| self.push(SplitPosition{ pos: *x, desync_type: DesyncType::Splitoob }));
-        desync_vecs.fake.iter().for_each(|x| self.push(SplitPosition{ pos: *x, desync_type: DesyncType::Fake }));
+impl Subcommands {
+  /// Listen addr, transparent mode and desync options of tcp subcommand
+  fn tcp_profile(self, matches: &ArgMatches) -> Option<(String, bool, Profile)> {
+    match self {
+      Self::Tcp { proxy_addr, fake_ttl, auto_ttl, fake_payload, udp_fake_payload, fooling, buf_size, timeout, disorder, split, disoob, splitoob, fake, tlsrec, mod_http, oob_data, transparent, ..} => {
+        let m = matches.subcommand_matches("tcp")?;
+        Some((proxy_addr, transparent, tcp_profile(m, fake_ttl, auto_ttl, fake_payload, udp_fake_payload, fooling, buf_size, timeout, oob_data, mod_http, DesyncVecs {
+          disorder, split, disoob, splitoob, fake, tlsrec
+        })))
       }
+      Self::Udp { tcp: Some(UdpSubcommand::Tcp { proxy_addr, fake_ttl, auto_ttl, fake_payload, udp_fake_payload, fooling, buf_size, timeout, disorder, split, disoob, splitoob, fake, tlsrec, mod_http, oob_data, transparent }), .. } => {
+        let m = matches.subcommand_matches("udp")?.subcommand_matches("tcp")?;
+        Some((proxy_addr, transparent, tcp_profile(m, fake_ttl, auto_ttl, fake_payload, udp_fake_payload, fooling, buf_size, timeout, oob_data, mod_http, DesyncVecs {
+          disorder, split, disoob, splitoob, fake, tlsrec
+        })))
+      }
+      Self::Udp { tcp: None, .. } | Self::Probe { .. } => None
     }
+  }
+}
 
-    fn create_server(proxy_addr: String, fake_ttl: u8, buf_size: usize, timeout: f32, oob_data: u8, desync_ves: DesyncVecs) -> ProxyServer {
-      let mut server = ProxyServer::new(SocketAddr::from_str(proxy_addr.as_str()).unwrap());
-      let mut desync_options = SplitPositions::new();
-      server.set_msg_buf_size(buf_size);
-      desync_options.push_split_pos(desync_ves);
-      server.bypass_options.append_options(desync_options);
-      server.bypass_options.fake_ttl = fake_ttl as u32;
-      server.bypass_options.oob_data = oob_data;
-      if timeout > 0.0 { server.bypass_options.timeout = Some(Duration::from_secs_f32(timeout)); }
-      assert!(server.bypass_options.at_least_one_option(), "You need to specify at least one option");
-      server
+impl Cmd {
+  pub fn load_config(&self) -> Result<Option<Config>, anyhow::Error> {
+    self.config.as_deref().map(Config::load).transpose()
+  }
+
+  /// Proxy server of tcp subcommand or proxy servers of config listeners.
+  /// Explicitly given options of tcp subcommand override options of --profile from config
+  pub fn proxy_servers(&self, matches: &ArgMatches, config: Option<&Config>) -> Result<Vec<ProxyServer>, anyhow::Error> {
+    let mut servers = self.tcp_or_config_servers(matches, config)?;
+    if let Some(workers) = self.workers {
+      if workers == 0 { bail!("--workers must be at least 1"); }
+      servers.iter_mut().for_each(|server| server.workers = workers);
+    }
+    if let Some(buf_pool) = self.buf_pool {
+      servers.iter_mut().for_each(|server| server.buf_pool = buf_pool);
     }
+    if self.no_splice {
+      servers.iter_mut().for_each(|server| server.splice = false);
+    }
+    Ok(servers)
+  }
 
-    match self {
-      Self::Tcp { proxy_addr, fake_ttl, buf_size, timeout, disorder, split, disoob, splitoob, fake, oob_data, ..} => {
-        #[allow(clippy::redundant_field_names)]
-        let server = create_server(proxy_addr, fake_ttl, buf_size, timeout, oob_data, DesyncVecs {
-          disorder: disorder, split: split, disoob: disoob, splitoob: splitoob, fake: fake
-        });
-        Ok(server)
-      }
-      Self::Udp { tcp, .. } => {
-        if let Some(tcp_opts) = tcp {
-          match tcp_opts {
-            UdpSubcommand::Tcp { proxy_addr, fake_ttl, buf_size, timeout, disorder, split, disoob, splitoob, fake, oob_data, } => {
-              #[allow(clippy::redundant_field_names)]
-              let server = create_server(proxy_addr, fake_ttl, buf_size, timeout, oob_data, DesyncVecs {
-                disorder: disorder, split: split, disoob: disoob, splitoob: splitoob, fake: fake
-              });
-              Ok(server)
-            }
-          }
-        } else { bail!("tcp subcommand not found"); }
+  fn tcp_or_config_servers(&self, matches: &ArgMatches, config: Option<&Config>) -> Result<Vec<ProxyServer>, anyhow::Error> {
+    let tcp = self.cmd.clone().and_then(|cmd| cmd.tcp_profile(matches));
+    let Some((proxy_addr, transparent, cli_profile)) = tcp else {
+      if self.profile.is_some() { bail!("--profile needs tcp subcommand"); }
+      let Some(config) = config else { return Ok(Vec::new()); };
+      let mut servers = config.proxy_servers()?;
+      if !self.auto.is_empty() {
+        let fallback = config.fallback(&self.auto)?;
+        servers.iter_mut().for_each(|server| server.fallback = fallback.clone());
       }
+      return Ok(servers);
+    };
+    let profile = match (config, &self.profile) {
+      (Some(config), Some(name)) => config.profile(name)?.clone(),
+      (Some(config), None) => config.profile.get(DEFAULT_PROFILE).cloned().unwrap_or_default(),
+      (None, Some(_)) => bail!("--profile needs --config"),
+      (None, None) => Profile::default()
+    };
+    let addr = SocketAddr::from_str(&proxy_addr).map_err(|e| anyhow!("wrong proxy addr {proxy_addr}: {e}"))?;
+    let mut server = profile.merge(cli_profile).proxy_server(addr, transparent)?;
+    if let Some(config) = config {
+      server.rules = Arc::new(config.rules()?);
+      server.fallback = config.fallback(&self.auto)?;
     }
+    Ok(vec![server])
   }
 }
 
@@ -236,15 +370,30 @@ impl TryInto<UdpBypassHelpData> for Subcommands {
         if !netns.is_empty() { udp::netns(netns.as_str()).unwrap(); }
         Ok(UdpBypassHelpData::new::<UDP_RECV_BUF_SIZE>(mark, nfqueue_num, fake_ttl))
       }
+      Self::Probe { .. } => bail!("udp subcommand not found")
     }
   }
 }
 
+#[cfg(feature = "udp-desync")]
+impl Cmd {
+  /// Udp options of udp subcommand or of config
+  pub fn udp_options(&self, config: Option<&Config>) -> Option<UdpBypassHelpData> {
+    if let Some(udp) = self.cmd.clone().and_then(|cmd| cmd.try_into().ok()) { return Some(udp); }
+    config?.udp.as_ref().map(|udp| {
+      if !udp.netns.is_empty() { udp::netns(udp.netns.as_str()).unwrap(); }
+      UdpBypassHelpData::new::<UDP_RECV_BUF_SIZE>(udp.mark, udp.nfqueue_num, udp.fake_ttl)
+    })
+  }
+}
+
 #[cfg(not(feature = "udp-desync"))]
 #[inline]
-pub fn is_udp_opts(cmd: &Subcommands) -> bool {
-  match cmd {
-    Subcommands::Tcp { udp, .. } => udp.is_some(),
-    Subcommands::Udp { .. } => true
-  }
+pub fn is_udp_opts(cmd: &Option<Subcommands>, config: Option<&Config>) -> bool {
+  let cmd_udp = match cmd {
+    Some(Subcommands::Tcp { udp, .. }) => udp.is_some(),
+    Some(Subcommands::Udp { .. }) => true,
+    Some(Subcommands::Probe { .. }) | None => false
+  };
+  cmd_udp || config.is_some_and(|config| config.udp.is_some())
 }
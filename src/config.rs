@@ -0,0 +1,370 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use serde::de::{self, Deserializer, Visitor};
+use serde::Deserialize;
+
+use crate::auto_ttl::AutoTtl;
+use crate::buf_pool::BUF_POOL_SIZE;
+use crate::bypass::{BypassOptions, DesyncType, FakePayload, Position, SplitPosition, SplitPositions};
+use crate::fallback::Fallback;
+use crate::fooling::Fooling;
+use crate::http::HttpMod;
+use crate::proxy_server::ProxyServer;
+use crate::rules::{Action, Rule, Rules};
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// TOML config file with listeners, named desync profiles and udp settings
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  pub listener: Vec<Listener>,
+  pub profile: HashMap<String, Profile>,
+  pub rule: Vec<RuleConfig>,
+  pub udp: Option<UdpConfig>
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Listener {
+  pub addr: SocketAddr,
+  #[serde(default = "default_profile")]
+  pub profile: String,
+  #[serde(default)]
+  pub transparent: bool,
+  /// Profiles tried one by one in auto mode if the server answers with failure
+  #[serde(default)]
+  pub auto: Vec<String>,
+  #[serde(default = "default_workers")]
+  pub workers: usize,
+  /// Registered io_uring buffers of every worker, 0 disables registration
+  #[serde(default = "default_buf_pool")]
+  pub buf_pool: usize,
+  /// Relay connections with splice after the first request
+  #[serde(default = "default_splice")]
+  pub splice: bool
+}
+
+/// Desync options, the same as options of tcp subcommand. Unset options have their default values
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Profile {
+  pub fake_ttl: Option<u8>,
+  pub auto_ttl: Option<u8>,
+  pub fake_payload: Option<FakePayload>,
+  pub udp_fake_payload: Option<FakePayload>,
+  pub fooling: Option<Fooling>,
+  pub buf_size: Option<usize>,
+  pub timeout: Option<f32>,
+  pub disorder: Option<Position>,
+  pub split: Vec<Position>,
+  pub disoob: Vec<Position>,
+  pub splitoob: Vec<Position>,
+  pub fake: Vec<Position>,
+  pub tlsrec: Vec<Position>,
+  pub mod_http: Vec<HttpMod>,
+  pub oob_data: Option<u8>
+}
+
+/// Connections to matched domains or addresses use options of profile instead of listener options.
+/// With desync = false they are relayed as is
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+  #[serde(default)]
+  pub domains: Vec<String>,
+  #[serde(default)]
+  pub ips: Vec<String>,
+  #[serde(default)]
+  pub hostlists: Vec<PathBuf>,
+  pub profile: Option<String>,
+  #[serde(default = "default_desync")]
+  pub desync: bool
+}
+
+/// Read only with udp-desync feature, without it config with udp settings is rejected
+#[cfg_attr(not(feature = "udp-desync"), allow(dead_code))]
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct UdpConfig {
+  #[serde(default = "default_udp_fake_ttl")]
+  pub fake_ttl: u8,
+  pub mark: i32,
+  pub nfqueue_num: u16,
+  #[serde(default)]
+  pub netns: String
+}
+
+fn default_profile() -> String { DEFAULT_PROFILE.into() }
+
+fn default_udp_fake_ttl() -> u8 { 6 }
+
+fn default_desync() -> bool { true }
+
+fn default_workers() -> usize { 1 }
+
+fn default_buf_pool() -> usize { BUF_POOL_SIZE }
+
+fn default_splice() -> bool { true }
+
+impl Config {
+  pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+    let data = std::fs::read_to_string(path).with_context(|| format!("can't read config {}", path.display()))?;
+    let config: Config = toml::from_str(&data).with_context(|| format!("wrong config {}", path.display()))?;
+    if let Some(listener) = config.listener.iter().find(|l| !config.profile.contains_key(&l.profile)) {
+      bail!("listener {} uses unknown profile: {}", listener.addr, listener.profile);
+    }
+    for listener in &config.listener {
+      if listener.workers == 0 { bail!("listener {} needs at least 1 worker", listener.addr); }
+      if let Some(name) = listener.auto.iter().find(|&name| !config.profile.contains_key(name)) {
+        bail!("listener {} uses unknown auto profile: {name}", listener.addr);
+      }
+    }
+    for (i, rule) in config.rule.iter().enumerate() {
+      match (&rule.profile, rule.desync) {
+        (Some(profile), true) if !config.profile.contains_key(profile) => bail!("rule {i} uses unknown profile: {profile}"),
+        (Some(_), true) | (None, false) => {}
+        _ => bail!("rule {i} needs either profile or desync = false")
+      }
+    }
+    Ok(config)
+  }
+
+  pub fn profile(&self, name: &str) -> Result<&Profile, anyhow::Error> {
+    self.profile.get(name).ok_or_else(|| anyhow!("unknown profile: {name}"))
+  }
+
+  /// Profile selected per connection by rule or auto mode. Buffers are allocated by listener before
+  /// the profile is known, so buf-size is set only by listener profile
+  fn connection_profile(&self, name: &str) -> Result<&Profile, anyhow::Error> {
+    let profile = self.profile(name)?;
+    if profile.buf_size.is_some() { bail!("profile {name} of rule or auto mode can't set buf-size, it is set by listener profile"); }
+    Ok(profile)
+  }
+
+  pub fn proxy_servers(&self) -> Result<Vec<ProxyServer>, anyhow::Error> {
+    let rules = Arc::new(self.rules()?);
+    self.listener.iter()
+      .map(|l| {
+        let mut server = self.profile(&l.profile)?.proxy_server(l.addr, l.transparent)?;
+        server.rules = rules.clone();
+        server.fallback = self.fallback(&l.auto)?;
+        server.workers = l.workers;
+        server.buf_pool = l.buf_pool;
+        server.splice = l.splice;
+        Ok(server)
+      })
+      .collect()
+  }
+
+  /// Auto mode with given profiles, None if there are no profiles
+  pub fn fallback(&self, names: &[String]) -> Result<Option<Arc<Fallback>>, anyhow::Error> {
+    if names.is_empty() { return Ok(None); }
+    let profiles = names.iter()
+      .map(|name| Ok((name.clone(), self.connection_profile(name)?.bypass_options().with_context(|| format!("wrong profile {name}"))?)))
+      .collect::<Result<_, anyhow::Error>>()?;
+    Ok(Some(Arc::new(Fallback::new(profiles))))
+  }
+
+  pub fn rules(&self) -> Result<Rules, anyhow::Error> {
+    self.rule.iter().map(|rule| {
+      let action = match &rule.profile {
+        Some(name) if rule.desync => Action::Desync(Box::new(self.connection_profile(name)?.bypass_options()?)),
+        _ => Action::NoDesync
+      };
+      let mut r = Rule::new(action);
+      r.domains = rule.domains.iter().map(|d| d.parse()).collect::<Result<_, _>>()?;
+      r.nets = rule.ips.iter().map(|ip| ip.parse()).collect::<Result<_, _>>()?;
+      for path in &rule.hostlists { r.load_hostlist(path)?; }
+      Ok(r)
+    }).collect::<Result<_, _>>().map(Rules)
+  }
+}
+
+impl Profile {
+  /// Options set in other replace options of self, positions are replaced for every desync type separately
+  pub fn merge(self, other: Profile) -> Profile {
+    fn vec_or<T>(a: Vec<T>, b: Vec<T>) -> Vec<T> { if b.is_empty() { a } else { b } }
+    Profile {
+      fake_ttl: other.fake_ttl.or(self.fake_ttl),
+      auto_ttl: other.auto_ttl.or(self.auto_ttl),
+      fake_payload: other.fake_payload.or(self.fake_payload),
+      udp_fake_payload: other.udp_fake_payload.or(self.udp_fake_payload),
+      fooling: other.fooling.or(self.fooling),
+      buf_size: other.buf_size.or(self.buf_size),
+      timeout: other.timeout.or(self.timeout),
+      disorder: other.disorder.or(self.disorder),
+      split: vec_or(self.split, other.split),
+      disoob: vec_or(self.disoob, other.disoob),
+      splitoob: vec_or(self.splitoob, other.splitoob),
+      fake: vec_or(self.fake, other.fake),
+      tlsrec: vec_or(self.tlsrec, other.tlsrec),
+      mod_http: vec_or(self.mod_http, other.mod_http),
+      oob_data: other.oob_data.or(self.oob_data)
+    }
+  }
+
+  pub fn proxy_server(&self, addr: SocketAddr, transparent: bool) -> Result<ProxyServer, anyhow::Error> {
+    let mut server = ProxyServer::new(addr);
+    server.transparent = transparent;
+    if let Some(buf_size) = self.buf_size { server.set_msg_buf_size(buf_size); }
+    server.bypass_options = self.bypass_options().with_context(|| format!("wrong options for {addr}"))?;
+    Ok(server)
+  }
+
+  pub fn bypass_options(&self) -> Result<BypassOptions, anyhow::Error> {
+    let mut options = BypassOptions::new();
+    options.append_options(self.split_positions());
+    options.http_mods = self.mod_http.clone();
+    if let Some(fake_ttl) = self.fake_ttl { options.fake_ttl = fake_ttl as u32; }
+    if let Some(delta) = self.auto_ttl {
+      options.auto_ttl = Some(AutoTtl::shared(delta).map_err(|e| anyhow!("can't enable auto ttl: {e}"))?);
+    }
+    if let Some(fake_payload) = &self.fake_payload { options.fake_payload = fake_payload.clone(); }
+    options.udp_fake_payload = self.udp_fake_payload.clone();
+    if let Some(fooling) = self.fooling { options.fooling = fooling; }
+    if let Some(oob_data) = self.oob_data { options.oob_data = oob_data; }
+    if let Some(timeout) = self.timeout.filter(|&t| t > 0.0) { options.timeout = Some(Duration::from_secs_f32(timeout)); }
+    if !options.at_least_one_option() { bail!("You need to specify at least one option"); }
+    Ok(options)
+  }
+
+  fn split_positions(&self) -> SplitPositions {
+    let mut positions = SplitPositions::new();
+    let mut push = |pos: &[Position], desync_type: DesyncType| {
+      positions.extend(pos.iter().map(|&pos| SplitPosition{ pos, desync_type: desync_type.clone() }));
+    };
+    push(self.disorder.as_slice(), DesyncType::Disorder);
+    push(&self.split, DesyncType::Split);
+    push(&self.disoob, DesyncType::Disoob);
+    push(&self.splitoob, DesyncType::Splitoob);
+    push(&self.fake, DesyncType::Fake);
+    push(&self.tlsrec, DesyncType::Tlsrec);
+    positions
+  }
+}
+
+/// Deserializes from string with FromStr. Integers are accepted as well for positions
+struct FromStrVisitor<T>(PhantomData<T>);
+
+impl<'de, T: FromStr<Err = anyhow::Error>> Visitor<'de> for FromStrVisitor<T> {
+  type Value = T;
+
+  fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("string") }
+
+  fn visit_str<E: de::Error>(self, v: &str) -> Result<T, E> { v.parse().map_err(E::custom) }
+
+  fn visit_i64<E: de::Error>(self, v: i64) -> Result<T, E> { self.visit_str(&v.to_string()) }
+}
+
+macro_rules! deserialize_from_str {
+  ($($t:ty),*) => {$(
+    impl<'de> Deserialize<'de> for $t {
+      fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(FromStrVisitor(PhantomData))
+      }
+    }
+  )*};
+}
+
+deserialize_from_str!(Position, FakePayload, Fooling, HttpMod);
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::bypass::PositionBase;
+
+  const CONFIG: &str = r#"
+    [[listener]]
+    addr = "127.0.0.1:6969"
+
+    [[listener]]
+    addr = "[::1]:6970"
+    profile = "fake"
+    transparent = true
+    auto = ["default"]
+    workers = 4
+    buf-pool = 0
+    splice = false
+
+    [profile.default]
+    split = ["s+1", -1]
+    mod-http = ["host-case"]
+
+    [profile.fake]
+    fake = [3]
+    fake-ttl = 4
+    fooling = "md5sig"
+    timeout = 1.5
+
+    [[rule]]
+    domains = ["*.googlevideo.com", "youtube.com"]
+    profile = "fake"
+
+    [[rule]]
+    ips = ["192.168.0.0/16", "::1"]
+    desync = false
+
+    [udp]
+    mark = 12345
+    nfqueue-num = 0
+  "#;
+
+  #[test]
+  fn parse_config() {
+    let config: Config = toml::from_str(CONFIG).unwrap();
+    let profile = config.profile("default").unwrap();
+    assert_eq!(profile.split, vec![
+      Position{ offset: 1, base: PositionBase::HostStart },
+      Position{ offset: -1, base: PositionBase::Abs }
+    ]);
+    assert_eq!(profile.mod_http, vec![HttpMod::HostCase]);
+    let servers = config.proxy_servers().unwrap();
+    assert_eq!(servers.len(), 2);
+    assert!(servers[1].transparent);
+    assert!(servers[0].fallback.is_none() && servers[1].fallback.is_some());
+    assert_eq!((servers[0].workers, servers[1].workers), (1, 4));
+    assert_eq!((servers[0].buf_pool, servers[1].buf_pool), (BUF_POOL_SIZE, 0));
+    assert!(servers[0].splice && !servers[1].splice);
+    assert_eq!(servers[1].bypass_options.fake_ttl, 4);
+    assert_eq!(servers[1].bypass_options.fooling, Fooling::Md5sig);
+    assert_eq!(config.udp.unwrap().fake_ttl, 6);
+    assert!(toml::from_str::<Config>("[profile.default]\nsplit = [\"x\"]").is_err());
+    assert!(toml::from_str::<Config>("[profile.default]\nunknown = 1").is_err());
+  }
+
+  #[test]
+  fn parse_rules() {
+    let config: Config = toml::from_str(CONFIG).unwrap();
+    let rules = config.rules().unwrap();
+    let selected = rules.select(Some("www.youtube.com"), None);
+    assert!(matches!(selected, Some(Action::Desync(options)) if options.fooling == Fooling::Md5sig));
+    assert!(matches!(rules.select(Some("example.com"), "::1".parse().ok()), Some(Action::NoDesync)));
+    assert!(rules.select(Some("example.com"), "10.0.0.1".parse().ok()).is_none());
+    assert!(toml::from_str::<Config>("[[rule]]\ndomains = [\"a b\"]\ndesync = false").unwrap().rules().is_err());
+    let buf_size = format!("{CONFIG}\n[profile.big]\nsplit = [1]\nbuf-size = 65536\n[[rule]]\nips = [\"10.0.0.0/8\"]\nprofile = \"big\"");
+    let config: Config = toml::from_str(&buf_size).unwrap();
+    assert!(config.rules().is_err());
+    assert!(config.fallback(&["big".into()]).is_err());
+    assert!(config.fallback(&["fake".into()]).unwrap().is_some());
+  }
+
+  #[test]
+  fn merge_profiles() {
+    let config: Config = toml::from_str(CONFIG).unwrap();
+    let cli = Profile{ fake_ttl: Some(8), split: vec!["2".parse().unwrap()], ..Default::default() };
+    let merged = config.profile("fake").unwrap().clone().merge(cli);
+    assert_eq!(merged.fake_ttl, Some(8));
+    assert_eq!(merged.fooling, Some(Fooling::Md5sig));
+    assert_eq!(merged.split.len(), 1);
+    assert_eq!(merged.fake.len(), 1);
+  }
+}
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::bypass::BypassOptions;
+use crate::tls::TLS_RECORD_HEADER_LEN;
+
+const TLS_ALERT_CONTENT_TYPE: u8 = 0x15;
+const MAX_CACHED_HOSTS: usize = 4096;
+
+/// Auto mode: if the server answers the first request with failure, the request is sent again
+/// over new connection with the next profile. The profile which worked is remembered for the host
+pub struct Fallback {
+  profiles: Vec<(String, BypassOptions)>,
+  /// Index of profile which worked for host, hosts which work with listener options aren't cached
+  cache: Mutex<HashMap<String, usize>>
+}
+
+impl fmt::Debug for Fallback {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_list().entries(self.profiles.iter().map(|(name, _)| name)).finish()
+  }
+}
+
+impl Fallback {
+  pub fn new(profiles: Vec<(String, BypassOptions)>) -> Self {
+    Self{ profiles, cache: Mutex::new(HashMap::new()) }
+  }
+
+  /// Order of attempts for host: the cached profile, listener options (None), then other profiles.
+  /// Without host nothing is cached
+  pub fn attempts(&self, host: Option<&str>) -> Vec<Option<usize>> {
+    let cached = host.and_then(|host| self.cache.lock().unwrap().get(host).copied());
+    let mut attempts = vec![cached];
+    attempts.extend(std::iter::once(None).chain((0..self.profiles.len()).map(Some)).filter(|&a| a != cached));
+    attempts
+  }
+
+  pub fn options(&self, attempt: Option<usize>) -> Option<&BypassOptions> {
+    attempt.map(|i| &self.profiles[i].1)
+  }
+
+  pub fn name(&self, attempt: Option<usize>) -> &str {
+    attempt.map_or("listener options", |i| self.profiles[i].0.as_str())
+  }
+
+  pub fn save(&self, host: &str, attempt: Option<usize>) {
+    let mut cache = self.cache.lock().unwrap();
+    match attempt {
+      Some(i) => {
+        if cache.len() >= MAX_CACHED_HOSTS && !cache.contains_key(host) { cache.clear(); }
+        cache.insert(host.to_owned(), i);
+      }
+      None => { cache.remove(host); }
+    }
+  }
+}
+
+/// Returns reason of failure if the first response of server looks like blocked connection
+pub fn blocked_response(response: &[u8], tls: bool) -> Option<&'static str> {
+  if response.is_empty() { return Some("connection closed"); }
+  if tls && response.len() >= TLS_RECORD_HEADER_LEN && response[0] == TLS_ALERT_CONTENT_TYPE { return Some("tls alert"); }
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn attempts_start_with_cached_profile() {
+    let fallback = Fallback::new(vec![("a".into(), BypassOptions::new()), ("b".into(), BypassOptions::new())]);
+    assert_eq!(fallback.attempts(Some("example.com")), vec![None, Some(0), Some(1)]);
+    fallback.save("example.com", Some(1));
+    assert_eq!(fallback.attempts(Some("example.com")), vec![Some(1), None, Some(0)]);
+    assert_eq!(fallback.attempts(None), vec![None, Some(0), Some(1)]);
+    fallback.save("example.com", None);
+    assert_eq!(fallback.attempts(Some("example.com")), vec![None, Some(0), Some(1)]);
+  }
+
+  #[test]
+  fn detect_blocked_response() {
+    assert_eq!(blocked_response(b"", false), Some("connection closed"));
+    assert_eq!(blocked_response(&[0x15, 3, 3, 0, 2, 2, 40], true), Some("tls alert"));
+    assert_eq!(blocked_response(&[0x16, 3, 3, 0, 2, 2, 0], true), None);
+    assert_eq!(blocked_response(b"HTTP/1.1 200 OK\r\n", false), None);
+  }
+}
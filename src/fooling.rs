@@ -0,0 +1,255 @@
+use std::io;
+use std::mem::{size_of, MaybeUninit};
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+use std::str::FromStr;
+
+use anyhow::bail;
+use log::trace;
+use socket2::{Domain, Protocol, Socket, Type};
+
+const TCP_RECV_QUEUE: libc::c_int = 1;
+const TCP_SEND_QUEUE: libc::c_int = 2;
+const TCP_REPAIR_OFF: libc::c_int = 0;
+const TCP_REPAIR_OFF_NO_WP: libc::c_int = -1;
+const TCP_HEADER_LEN: usize = 20;
+const TCP_FLAGS_PSH_ACK: u8 = 0x18;
+const TCP_WINDOW: u16 = 0xfaf0;
+const BADSEQ_SEQ_DELTA: u32 = 10000;
+const BADSEQ_ACK_DELTA: u32 = 66000;
+const MD5SIG_KEY: &[u8] = b"rustpass-dpi";
+
+/// How fake packets are made harmless for the server
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Fooling {
+  /// Fake packet with fake_ttl doesn't reach the server
+  #[default]
+  Ttl,
+  /// Fake packet with TCP MD5 signature option is dropped by the server
+  Md5sig,
+  /// Fake packet with wrong seq and ack numbers is out of the server window. Needs root
+  Badseq,
+  /// Fake packet with wrong checksum is dropped by the server. Needs root
+  Badsum
+}
+
+impl FromStr for Fooling {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Ok(match s {
+      "ttl" => Self::Ttl,
+      "md5sig" => Self::Md5sig,
+      "badseq" => Self::Badseq,
+      "badsum" => Self::Badsum,
+      _ => bail!("unknown fooling: {s}, expected one of: ttl, md5sig, badseq, badsum")
+    })
+  }
+}
+
+#[repr(C)]
+struct TcpMd5sig {
+  tcpm_addr: libc::sockaddr_storage,
+  tcpm_flags: u8,
+  tcpm_prefixlen: u8,
+  tcpm_keylen: u16,
+  tcpm_ifindex: libc::c_int,
+  tcpm_key: [u8; libc::TCP_MD5SIG_MAXKEYLEN]
+}
+
+/// Adds TCP MD5 signature key for the peer of socket or removes it.
+/// All segments sent while key is set have MD5 signature option
+pub fn set_md5sig(fd: RawFd, enable: bool) -> io::Result<()> {
+  let sock = unsafe { Socket::from_raw_fd(fd) };
+  let peer = sock.peer_addr();
+  let _ = sock.into_raw_fd();
+  let peer = peer?;
+  let mut md5sig: TcpMd5sig = unsafe { MaybeUninit::zeroed().assume_init() };
+  unsafe {
+    std::ptr::copy_nonoverlapping(peer.as_ptr() as *const u8, &mut md5sig.tcpm_addr as *mut _ as *mut u8, peer.len() as usize);
+  }
+  if enable {
+    md5sig.tcpm_keylen = MD5SIG_KEY.len() as u16;
+    md5sig.tcpm_key[..MD5SIG_KEY.len()].copy_from_slice(MD5SIG_KEY);
+  }
+  set_opt(fd, libc::IPPROTO_TCP, libc::TCP_MD5SIG, &md5sig)
+}
+
+/// Sends fake TCP segment with data via raw socket before the next data of the connection.
+/// Segment has wrong seq and ack for Badseq or wrong checksum for Badsum
+pub fn send_raw_fake(fd: RawFd, data: &[u8], fooling: Fooling) -> io::Result<()> {
+  let sock = unsafe { Socket::from_raw_fd(fd) };
+  let addrs = sock.local_addr().and_then(|local| Ok((local, sock.peer_addr()?)));
+  let _ = sock.into_raw_fd();
+  let (local, peer) = addrs?;
+  let (Some(src), Some(dst)) = (local.as_socket().map(unmap), peer.as_socket().map(unmap)) else {
+    return Err(io::Error::new(io::ErrorKind::InvalidInput, "not an inet socket"));
+  };
+  let (seq, ack) = tcp_seq_ack(fd)?;
+  let (seq, ack) = fake_seq_ack(seq, ack, fooling);
+  let segment = tcp_segment(src, dst, seq, ack, data, fooling == Fooling::Badsum);
+  let raw = Socket::new(Domain::for_address(dst), Type::RAW, Some(Protocol::TCP))?;
+  raw.send_to(&segment, &SocketAddr::new(dst.ip(), 0).into())?;
+  trace!("raw fake segment with seq: {seq}, ack: {ack}, len: {} was sent to {dst}", data.len());
+  Ok(())
+}
+
+/// Dual-stack socket connected to IPv4 peer has v4-mapped addresses, but raw IPv6 socket can't send to them
+/// and checksum of IPv4 segment is computed over IPv4 addresses
+fn unmap(addr: SocketAddr) -> SocketAddr {
+  SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Seq and ack of fake segment, with Badseq they are out of the server window
+fn fake_seq_ack(seq: u32, ack: u32, fooling: Fooling) -> (u32, u32) {
+  match fooling {
+    Fooling::Badseq => (seq.wrapping_sub(BADSEQ_SEQ_DELTA), ack.wrapping_sub(BADSEQ_ACK_DELTA)),
+    _ => (seq, ack)
+  }
+}
+
+/// Returns seq of the next byte written to socket and ack of the next byte expected from peer.
+/// Queue sequence numbers are readable only in TCP_REPAIR mode, it needs CAP_NET_ADMIN.
+/// Socket is in repair mode only for these reads and it is turned off on error too. Meanwhile nothing is written
+/// to the socket, in-flight and queued data isn't dropped, the kernel just may hold back its segments
+/// until repair mode is off
+fn tcp_seq_ack(fd: RawFd) -> io::Result<(u32, u32)> {
+  set_opt(fd, libc::IPPROTO_TCP, libc::TCP_REPAIR, &1)?;
+  let seq_ack = (|| {
+    set_opt(fd, libc::IPPROTO_TCP, libc::TCP_REPAIR_QUEUE, &TCP_SEND_QUEUE)?;
+    let seq: u32 = get_opt(fd, libc::IPPROTO_TCP, libc::TCP_QUEUE_SEQ)?;
+    set_opt(fd, libc::IPPROTO_TCP, libc::TCP_REPAIR_QUEUE, &TCP_RECV_QUEUE)?;
+    let ack: u32 = get_opt(fd, libc::IPPROTO_TCP, libc::TCP_QUEUE_SEQ)?;
+    Ok((seq, ack))
+  })();
+  // TCP_REPAIR_OFF_NO_WP doesn't send window probe, but old kernels don't support it
+  set_opt(fd, libc::IPPROTO_TCP, libc::TCP_REPAIR, &TCP_REPAIR_OFF_NO_WP)
+    .or_else(|_| set_opt(fd, libc::IPPROTO_TCP, libc::TCP_REPAIR, &TCP_REPAIR_OFF))?;
+  seq_ack
+}
+
+fn tcp_segment(src: SocketAddr, dst: SocketAddr, seq: u32, ack: u32, data: &[u8], bad_checksum: bool) -> Vec<u8> {
+  let mut segment = Vec::with_capacity(TCP_HEADER_LEN + data.len());
+  segment.extend_from_slice(&src.port().to_be_bytes());
+  segment.extend_from_slice(&dst.port().to_be_bytes());
+  segment.extend_from_slice(&seq.to_be_bytes());
+  segment.extend_from_slice(&ack.to_be_bytes());
+  segment.push(((TCP_HEADER_LEN / 4) as u8) << 4);
+  segment.push(TCP_FLAGS_PSH_ACK);
+  segment.extend_from_slice(&TCP_WINDOW.to_be_bytes());
+  segment.extend_from_slice(&[0, 0, 0, 0]);
+  segment.extend_from_slice(data);
+  let mut checksum = tcp_checksum(src.ip(), dst.ip(), &segment);
+  if bad_checksum { checksum = checksum.wrapping_add(1); }
+  segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+  segment
+}
+
+fn tcp_checksum(src: IpAddr, dst: IpAddr, segment: &[u8]) -> u16 {
+  let mut pseudo = Vec::with_capacity(40);
+  match (src, dst) {
+    (IpAddr::V4(src), IpAddr::V4(dst)) => {
+      pseudo.extend_from_slice(&src.octets());
+      pseudo.extend_from_slice(&dst.octets());
+      pseudo.extend_from_slice(&[0, libc::IPPROTO_TCP as u8]);
+      pseudo.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+    }
+    (src, dst) => {
+      pseudo.extend_from_slice(&to_ipv6(src));
+      pseudo.extend_from_slice(&to_ipv6(dst));
+      pseudo.extend_from_slice(&(segment.len() as u32).to_be_bytes());
+      pseudo.extend_from_slice(&[0, 0, 0, libc::IPPROTO_TCP as u8]);
+    }
+  }
+  let mut sum: u32 = pseudo.chunks(2).chain(segment.chunks(2))
+    .map(|w| u16::from_be_bytes([w[0], *w.get(1).unwrap_or(&0)]) as u32)
+    .sum();
+  while sum >> 16 != 0 { sum = (sum & 0xffff) + (sum >> 16); }
+  !(sum as u16)
+}
+
+fn to_ipv6(ip: IpAddr) -> [u8; 16] {
+  match ip {
+    IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+    IpAddr::V6(ip) => ip.octets()
+  }
+}
+
+fn set_opt<T>(fd: RawFd, level: libc::c_int, opt: libc::c_int, val: &T) -> io::Result<()> {
+  let ret = unsafe { libc::setsockopt(fd, level, opt, val as *const T as _, size_of::<T>() as _) };
+  if ret < 0 { return Err(io::Error::last_os_error()); }
+  Ok(())
+}
+
+fn get_opt<T: Copy>(fd: RawFd, level: libc::c_int, opt: libc::c_int) -> io::Result<T> {
+  let mut val = MaybeUninit::<T>::zeroed();
+  let mut len = size_of::<T>() as libc::socklen_t;
+  let ret = unsafe { libc::getsockopt(fd, level, opt, val.as_mut_ptr() as _, &mut len) };
+  if ret < 0 { return Err(io::Error::last_os_error()); }
+  Ok(unsafe { val.assume_init() })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn segment_checksum() {
+    let src: SocketAddr = "192.168.1.2:40000".parse().unwrap();
+    let dst: SocketAddr = "93.184.216.34:443".parse().unwrap();
+    for data in [&b"odd"[..], b"even"] {
+      let segment = tcp_segment(src, dst, 1, 2, data, false);
+      assert_eq!(tcp_checksum(src.ip(), dst.ip(), &segment), 0);
+      let segment = tcp_segment(src, dst, 1, 2, data, true);
+      assert_ne!(tcp_checksum(src.ip(), dst.ip(), &segment), 0);
+    }
+  }
+
+  #[test]
+  fn segment_layout() {
+    let src: SocketAddr = "192.168.1.2:40000".parse().unwrap();
+    let dst: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+    let segment = tcp_segment(src, dst, 0x01020304, 0x0a0b0c0d, b"data", false);
+    assert_eq!(segment.len(), TCP_HEADER_LEN + 4);
+    assert_eq!(&segment[..16], &[0x9c, 0x40, 0x01, 0xbb, 1, 2, 3, 4, 0x0a, 0x0b, 0x0c, 0x0d, 0x50, 0x18, 0xfa, 0xf0]);
+    // urgent pointer is zero, data follows the header without options
+    assert_eq!(&segment[18..], b"\0\0data");
+    let checksum = u16::from_be_bytes([segment[16], segment[17]]);
+    let bad = tcp_segment(src, dst, 0x01020304, 0x0a0b0c0d, b"data", true);
+    assert_eq!(&bad[..16], &segment[..16]);
+    assert_eq!(u16::from_be_bytes([bad[16], bad[17]]), checksum.wrapping_add(1));
+    assert_eq!(&bad[18..], &segment[18..]);
+  }
+
+  #[test]
+  fn segment_to_v4_mapped_peer() {
+    let src = unmap("[::ffff:192.168.1.2]:40000".parse().unwrap());
+    let dst = unmap("[::ffff:93.184.216.34]:443".parse().unwrap());
+    assert_eq!((src, dst), ("192.168.1.2:40000".parse().unwrap(), "93.184.216.34:443".parse().unwrap()));
+    assert!(Domain::for_address(dst) == Domain::IPV4);
+    let segment = tcp_segment(src, dst, 1, 2, b"data", false);
+    assert_eq!(tcp_checksum(src.ip(), dst.ip(), &segment), 0);
+    let v6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+    assert_eq!(unmap(v6), v6);
+  }
+
+  #[test]
+  fn badseq_numbers() {
+    assert_eq!(fake_seq_ack(100_000, 100_000, Fooling::Badseq), (90_000, 34_000));
+    assert_eq!(fake_seq_ack(5, 5, Fooling::Badseq), (5u32.wrapping_sub(10000), 5u32.wrapping_sub(66000)));
+    for fooling in [Fooling::Ttl, Fooling::Md5sig, Fooling::Badsum] {
+      assert_eq!(fake_seq_ack(5, 6, fooling), (5, 6));
+    }
+  }
+
+  #[test]
+  fn md5sig_layout() {
+    // struct tcp_md5sig of linux/tcp.h
+    assert_eq!(size_of::<TcpMd5sig>(), 216);
+    assert_eq!(std::mem::offset_of!(TcpMd5sig, tcpm_flags), 128);
+    assert_eq!(std::mem::offset_of!(TcpMd5sig, tcpm_prefixlen), 129);
+    assert_eq!(std::mem::offset_of!(TcpMd5sig, tcpm_keylen), 130);
+    assert_eq!(std::mem::offset_of!(TcpMd5sig, tcpm_ifindex), 132);
+    assert_eq!(std::mem::offset_of!(TcpMd5sig, tcpm_key), 136);
+  }
+}
@@ -0,0 +1,123 @@
+use std::ops::Range;
+use std::str::FromStr;
+
+use anyhow::bail;
+
+const HTTP_METHODS: [&[u8]; 9] = [b"GET ", b"POST ", b"HEAD ", b"PUT ", b"DELETE ", b"OPTIONS ", b"PATCH ", b"TRACE ", b"CONNECT "];
+const HOST_HEADER: &[u8] = b"\r\nhost:";
+const EXTRA_SPACE: &[u8] = b" \t ";
+
+/// Host header modifications for the first http request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HttpMod {
+  /// "Host" -> "hOsT"
+  HostCase,
+  /// "example.com" -> "eXaMpLe.cOm"
+  DomainCase,
+  /// "Host: example.com" -> "Host:example.com"
+  NoSpace,
+  /// "Host: example.com" -> "Host: \t example.com"
+  ExtraSpace
+}
+
+impl FromStr for HttpMod {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Ok(match s {
+      "host-case" => Self::HostCase,
+      "domain-case" => Self::DomainCase,
+      "no-space" => Self::NoSpace,
+      "extra-space" => Self::ExtraSpace,
+      _ => bail!("unknown http mod: {s}, expected one of: host-case, domain-case, no-space, extra-space")
+    })
+  }
+}
+
+pub fn is_http_request(input: &[u8]) -> bool {
+  HTTP_METHODS.iter().any(|method| input.starts_with(method))
+}
+
+struct HostHeader {
+  name: Range<usize>,
+  value: Range<usize>,
+  domain: Range<usize>
+}
+
+fn find_host_header(input: &[u8]) -> Option<HostHeader> {
+  let head_end = input.windows(4).position(|w| w == b"\r\n\r\n").map_or(input.len(), |pos| pos + 2);
+  let name_start = input[..head_end].windows(HOST_HEADER.len())
+    .position(|w| w.eq_ignore_ascii_case(HOST_HEADER))? + 2;
+  let name = name_start..name_start + HOST_HEADER.len() - 3;
+  let line_end = name.end + input[name.end..head_end].windows(2).position(|w| w == b"\r\n")?;
+  let value_start = name.end + 1 + input[name.end + 1..line_end].iter().take_while(|b| b.is_ascii_whitespace()).count();
+  let value_end = line_end - input[value_start..line_end].iter().rev().take_while(|b| b.is_ascii_whitespace()).count();
+  let value = &input[value_start..value_end];
+  let domain_len = if value.starts_with(b"[") {
+    value.iter().position(|&b| b == b']').map_or(value.len(), |pos| pos + 1)
+  } else { value.iter().position(|&b| b == b':').unwrap_or(value.len()) };
+  Some(HostHeader{ name, value: value_start..value_end, domain: value_start..value_start + domain_len })
+}
+
+/// Position of domain in Host header of http request
+pub fn host_range(input: &[u8]) -> Option<Range<usize>> {
+  find_host_header(input).map(|host| host.domain)
+}
+
+/// Applies mods to Host header of http request in buf[..size]. Returns new size of data in buf
+pub fn mangle_host(buf: &mut Vec<u8>, size: usize, mods: &[HttpMod]) -> usize {
+  let Some(host) = find_host_header(&buf[..size]) else { return size; };
+  let mut out = Vec::with_capacity(size + EXTRA_SPACE.len());
+  out.extend_from_slice(&buf[..host.name.start]);
+  let mut name = buf[host.name.clone()].to_vec();
+  if mods.contains(&HttpMod::HostCase) { mix_case(&mut name); }
+  out.extend_from_slice(&name);
+  out.push(b':');
+  if mods.contains(&HttpMod::ExtraSpace) { out.extend_from_slice(EXTRA_SPACE); }
+  else if !mods.contains(&HttpMod::NoSpace) { out.extend_from_slice(&buf[host.name.end + 1..host.value.start]); }
+  let mut domain = buf[host.domain.clone()].to_vec();
+  if mods.contains(&HttpMod::DomainCase) { mix_case(&mut domain); }
+  out.extend_from_slice(&domain);
+  out.extend_from_slice(&buf[host.domain.end..size]);
+  if buf.len() < out.len() { buf.resize(out.len(), 0); }
+  buf[..out.len()].copy_from_slice(&out);
+  out.len()
+}
+
+fn mix_case(input: &mut [u8]) {
+  input.iter_mut().enumerate().for_each(|(i, b)| {
+    *b = if i % 2 == 0 { b.to_ascii_lowercase() } else { b.to_ascii_uppercase() };
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: example.com:8080\r\nAccept: */*\r\n\r\n";
+
+  fn mangle(mods: &[HttpMod]) -> Vec<u8> {
+    let mut buf = REQUEST.to_vec();
+    let size = mangle_host(&mut buf, REQUEST.len(), mods);
+    buf.truncate(size);
+    buf
+  }
+
+  #[test]
+  fn find_host() {
+    assert!(is_http_request(REQUEST));
+    assert!(!is_http_request(b"\x16\x03\x01"));
+    assert_eq!(&REQUEST[host_range(REQUEST).unwrap()], b"example.com");
+    let request = b"GET / HTTP/1.1\r\nhOsT:[::1]:80\r\n\r\n";
+    assert_eq!(&request[host_range(request).unwrap()], b"[::1]");
+    assert!(host_range(b"GET / HTTP/1.1\r\nAccept: */*\r\n\r\nHost: example.com\r\n").is_none());
+  }
+
+  #[test]
+  fn mangle_host_header() {
+    assert_eq!(mangle(&[HttpMod::HostCase]), b"GET / HTTP/1.1\r\nhOsT: example.com:8080\r\nAccept: */*\r\n\r\n");
+    assert_eq!(mangle(&[HttpMod::DomainCase, HttpMod::NoSpace]), b"GET / HTTP/1.1\r\nHost:eXaMpLe.cOm:8080\r\nAccept: */*\r\n\r\n");
+    assert_eq!(mangle(&[HttpMod::ExtraSpace]), b"GET / HTTP/1.1\r\nHost: \t example.com:8080\r\nAccept: */*\r\n\r\n");
+    assert_eq!(mangle(&[]), REQUEST);
+  }
+}
@@ -0,0 +1,154 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{anyhow, bail};
+use log::debug;
+use tokio_uring::buf::BoundedBuf;
+use tokio_uring::net::TcpStream;
+
+use crate::socks::SocksAddr;
+
+pub const HTTP_CONNECT_METHOD: &[u8] = b"CONNECT ";
+const HTTP_HEAD_END: &[u8] = b"\r\n\r\n";
+
+#[derive(Debug)]
+pub enum HttpConnectPhase {
+  ConnectReq,
+  ConnectRep,
+  Proxing
+}
+
+pub struct HttpConnect {
+  pub phase: HttpConnectPhase,
+  pub proxy_addr: SocksAddr,
+  pub proxy_stream: Option<TcpStream>,
+  pub client_stream: TcpStream
+}
+
+impl HttpConnect {
+  pub fn is_connect_req(input: &[u8]) -> bool {
+    let len = input.len().min(HTTP_CONNECT_METHOD.len());
+    input[..len] == HTTP_CONNECT_METHOD[..len]
+  }
+
+  /// Reads request head until empty line, input[..n] is the already received part of it.
+  /// Returns data received after the head, e.g. ClientHello sent without waiting for reply, it is the first data of the client
+  pub async fn read_connect_req(mut input: Vec<u8>, mut n: usize, client_stream: TcpStream) -> Result<(Self, Vec<u8>), anyhow::Error> {
+    while find_head_end(&input[..n]).is_none() {
+      if n == input.len() {
+        reply(&client_stream, "431 Request Header Fields Too Large").await?;
+        bail!("http connect request is bigger than buffer: {n}");
+      }
+      let (result, slice) = client_stream.read(input.slice(n..)).await;
+      input = slice.into_inner();
+      let read = result?;
+      if read == 0 { bail!("client closed connection before end of http connect request"); }
+      n += read;
+    }
+    let head_end = find_head_end(&input[..n]).unwrap();
+    let proxy_addr = match HttpConnect::parse_connect_req(&input[..head_end]) {
+      Ok(addr) => addr,
+      Err((status, e)) => {
+        reply(&client_stream, status).await?;
+        return Err(e);
+      }
+    };
+    debug!("http connect request to {proxy_addr:?}");
+    Ok((Self{
+      phase: HttpConnectPhase::ConnectReq,
+      proxy_addr,
+      proxy_stream: None,
+      client_stream
+    }, input[head_end..n].to_vec()))
+  }
+
+  /// Parses request line of http request head, returns destination or response status
+  pub fn parse_connect_req(head: &[u8]) -> Result<SocksAddr, (&'static str, anyhow::Error)> {
+    let bad_request = |e| ("400 Bad Request", e);
+    let head = std::str::from_utf8(head).map_err(|e| bad_request(anyhow::Error::new(e)))?;
+    let request_line = head.lines().next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+      return Err(bad_request(anyhow!("wrong http request line: {request_line:?}")));
+    };
+    if method != "CONNECT" {
+      return Err(("405 Method Not Allowed", anyhow!("unsupported http method: {method}")));
+    }
+    if !version.starts_with("HTTP/1.") {
+      return Err(("505 HTTP Version Not Supported", anyhow!("unsupported http version: {version}")));
+    }
+    let Some((host, port)) = target.rsplit_once(':') else {
+      return Err(bad_request(anyhow!("http connect target without port: {target}")));
+    };
+    let port = port.parse::<u16>().map_err(|e| bad_request(anyhow::Error::new(e)))?;
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    if host.is_empty() { return Err(bad_request(anyhow!("http connect target without host: {target}"))); }
+    Ok(match host.parse::<IpAddr>() {
+      Ok(ip) => SocksAddr::Ip(SocketAddr::new(ip, port)),
+      Err(_) => SocksAddr::Domain(host.to_owned(), port)
+    })
+  }
+
+  pub async fn connect_to_dst(&mut self) -> Result<(), anyhow::Error> {
+    match &self.phase {
+      HttpConnectPhase::ConnectReq => {
+        let connected = match self.proxy_addr.resolve().await {
+          Ok(dst) => TcpStream::connect(dst).await,
+          Err(e) => Err(e)
+        };
+        match connected {
+          Ok(pr_stream) => {
+            reply(&self.client_stream, "200 Connection established").await?;
+            self.proxy_stream = Some(pr_stream);
+            self.phase = HttpConnectPhase::ConnectRep;
+          }
+          Err(e) => {
+            reply(&self.client_stream, "502 Bad Gateway").await?;
+            return Err(anyhow::Error::new(e));
+          }
+        }
+      }
+      phase => bail!("Http connect Phase for connect must be {:?}, but current: {:?}", HttpConnectPhase::ConnectReq, phase)
+    }
+    Ok(())
+  }
+}
+
+fn find_head_end(input: &[u8]) -> Option<usize> {
+  input.windows(HTTP_HEAD_END.len()).position(|w| w == HTTP_HEAD_END).map(|pos| pos + HTTP_HEAD_END.len())
+}
+
+async fn reply(client_stream: &TcpStream, status: &str) -> std::io::Result<()> {
+  let (res, _) = client_stream.write(format!("HTTP/1.1 {status}\r\n\r\n").into_bytes()).submit().await;
+  res.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(head: &str) -> Result<SocksAddr, &'static str> {
+    HttpConnect::parse_connect_req(head.as_bytes()).map_err(|(status, _)| status)
+  }
+
+  #[test]
+  fn parse_http_connect_requests() {
+    let addr = parse("CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n").unwrap();
+    assert!(matches!(addr, SocksAddr::Domain(host, 443) if host == "example.com"));
+    let addr = parse("CONNECT 127.0.0.1:8443 HTTP/1.0\r\n\r\n").unwrap();
+    assert!(matches!(addr, SocksAddr::Ip(addr) if addr == "127.0.0.1:8443".parse().unwrap()));
+    let addr = parse("CONNECT [2001:db8::1]:443 HTTP/1.1\r\n\r\n").unwrap();
+    assert!(matches!(addr, SocksAddr::Ip(addr) if addr == "[2001:db8::1]:443".parse().unwrap()));
+    assert_eq!(parse("CONNECT example.com HTTP/1.1\r\n\r\n").unwrap_err(), "400 Bad Request");
+    assert_eq!(parse("CONNECT example.com:https HTTP/1.1\r\n\r\n").unwrap_err(), "400 Bad Request");
+    assert_eq!(parse("CONNECT :443 HTTP/1.1\r\n\r\n").unwrap_err(), "400 Bad Request");
+    assert_eq!(parse("GET http://example.com/ HTTP/1.1\r\n\r\n").unwrap_err(), "405 Method Not Allowed");
+    assert_eq!(parse("connect example.com:443 HTTP/1.1\r\n\r\n").unwrap_err(), "405 Method Not Allowed");
+    assert_eq!(parse("CONNECT example.com:443 HTTP/2\r\n\r\n").unwrap_err(), "505 HTTP Version Not Supported");
+  }
+
+  #[test]
+  fn http_head_end() {
+    assert_eq!(find_head_end(b"CONNECT a:1 HTTP/1.1\r\n\r\n\x16\x03\x01"), Some(24));
+    assert_eq!(find_head_end(b"CONNECT a:1 HTTP/1.1\r\n"), None);
+  }
+}
@@ -1,17 +1,35 @@
+mod auto_ttl;
+mod buf_pool;
 mod bypass;
 mod cmd;
+mod config;
+mod fallback;
+mod fooling;
+mod http;
+mod http_connect;
+mod probe;
 mod proxy_server;
+mod rules;
+mod shutdown;
 mod socks;
+mod splice;
+mod tls;
+mod transparent;
+mod udp_relay;
+
+use std::process;
+use std::thread;
 
 use env_logger::Env;
 #[allow(unused_imports)]
-use log::{debug, info};
+use log::{debug, info, error};
 use structopt::StructOpt;
 use cfg_block::cfg_block;
 
-use cmd::Cmd;
+use cmd::{Cmd, Subcommands};
 use proxy_server::ProxyServer;
 
+#[cfg(feature = "udp-desync")]
 macro_rules! root_block {
   ($bl: expr) => {
     #[cfg(feature = "suid")]
@@ -22,14 +40,36 @@ macro_rules! root_block {
   };
 }
 
+/// Runs every worker of every proxy server in its own thread until shutdown.
+/// If one of workers fails, shutdown of others is requested. Returns false if some worker failed
+fn start_servers(servers: Vec<ProxyServer>) -> bool {
+  thread::scope(|s| {
+    let mut workers = Vec::new();
+    for server in servers {
+      info!("Desync options:\n{:#?}", server);
+      for _ in 0..server.workers {
+        let server = server.clone();
+        let worker = thread::Builder::new().name("tcp-desync".into()).spawn_scoped(s, move || {
+          let addr = server.server_addr;
+          server.start_server().inspect_err(|e| {
+            error!("{addr}: {e:#}");
+            shutdown::request();
+          }).is_ok()
+        }).expect("failed to spawn thread");
+        workers.push(worker);
+      }
+    }
+    workers.into_iter().map(|worker| worker.join().unwrap_or(false)).filter(|ok| !ok).count() == 0
+  })
+}
+
 cfg_block! {
   #[cfg(feature = "udp-desync")] {
-    use std::thread;
-
     mod udp;
     use udp::UdpBypassHelpData;
+    /// Runs proxy servers and udp desync until shutdown. Returns false if some of them failed
     #[allow(unused_variables)]
-    fn run_bypassing(server: Option<ProxyServer>, udp_options: Option<UdpBypassHelpData>, app: String) {
+    fn run_bypassing(servers: Vec<ProxyServer>, udp_options: Option<UdpBypassHelpData>, app: String) -> bool {
       #[cfg(feature = "suid")] {
         Command::new("bash")
           .arg("-c")
@@ -40,27 +80,38 @@ cfg_block! {
           .expect("Failed to run app");
       }
       thread::scope(|s| {
-        thread::Builder::new().name("tcp-desync".into()).spawn_scoped(s, || {
-          if let Some(tcp_opts) = server {
-            info!("Desync options:\n{:#?}", tcp_opts);
-            tcp_opts.start_server();
-          }
-        }).expect("failed to spawn thread");
+        let tcp = s.spawn(|| start_servers(servers));
 
-        thread::Builder::new().name("udp-desync".into()).spawn_scoped(s, || {
-          if let Some(mut udp_opts) = udp_options {
-            #[cfg(not(feature = "suid"))]
-            assert_eq!(unsafe { libc::getuid() }, 0, "You need to be a root");
+        let udp = thread::Builder::new().name("udp-desync".into()).spawn_scoped(s, || {
+          let Some(mut udp_opts) = udp_options else { return true; };
+          #[cfg(not(feature = "suid"))]
+          assert_eq!(unsafe { libc::getuid() }, 0, "You need to be a root");
 
-            info!("Udp desync options:\n{:#?}", udp_opts);
-            root_block!({
-              assert_eq!(unsafe { libc::geteuid() }, 0, "euid must be 0, maybe you don't have suid bit");
-              udp_opts.init_queue().unwrap();
-              udp_opts.run_nfq_loop();
-            });
+          info!("Udp desync options:\n{:#?}", udp_opts);
+          let ok;
+          root_block!({
+            assert_eq!(unsafe { libc::geteuid() }, 0, "euid must be 0, maybe you don't have suid bit");
+            ok = match udp_opts.init_queue() {
+              // queue is destroyed when the loop ends, it ends by itself only on error
+              Ok(()) => {
+                udp_opts.run_nfq_loop(shutdown::event_fd());
+                shutdown::is_requested()
+              }
+              Err(e) => {
+                error!("udp desync: {e:#}");
+                false
+              }
+            };
+          });
+          if !ok {
+            error!("udp desync is stopped");
+            shutdown::request();
           }
+          ok
         }).expect("failed to spawn thread");
-      });
+        let tcp_ok = tcp.join().unwrap_or(false);
+        udp.join().unwrap_or(false) && tcp_ok
+      })
     }
   }
   #[cfg(feature = "suid")] {
@@ -80,26 +131,35 @@ fn main() {
     };
     set_user_uid();
   }
-  let opt = Cmd::from_args();
+  let matches = Cmd::clap().get_matches();
+  let opt = Cmd::from_clap(&matches);
   #[cfg(debug_assertions)] { log::trace!("opt: {:#?}", opt); }
   let app = opt.clone().run_app.unwrap_or(String::new());
   if !app.is_empty() && !cfg!(feature = "suid") {
     panic!("To use --run-app option you need to compile rustpass-dpi with --features suid");
   }
-  let server: Option<ProxyServer> = opt.clone().cmd.try_into().ok();
+  if let Some(Subcommands::Probe { hosts, fake_ttl, timeout }) = opt.cmd.clone() {
+    probe::run(hosts, &fake_ttl, timeout).unwrap_or_else(|e| panic!("{e:#}"));
+    return;
+  }
+  shutdown::handle_signals().expect("failed to handle signals");
+  let config = opt.load_config().unwrap_or_else(|e| panic!("{e:#}"));
+  let servers = opt.proxy_servers(&matches, config.as_ref()).unwrap_or_else(|e| panic!("{e:#}"));
   #[cfg(feature = "udp-desync")] {
+    #[allow(clippy::needless_late_init)]
     let udp_options: Option<UdpBypassHelpData>;
-    root_block!(udp_options = opt.cmd.try_into().ok());
-    run_bypassing(server, udp_options, app);
+    root_block!(udp_options = opt.udp_options(config.as_ref()));
+    assert!(!servers.is_empty() || udp_options.is_some(), "You need to specify tcp or udp subcommand or --config");
+    if !run_bypassing(servers, udp_options, app) { process::exit(1); }
   }
 
   #[cfg(not(feature = "udp-desync"))] {
     use cmd::is_udp_opts;
     assert!(
-      !is_udp_opts(&opt.cmd),
+      !is_udp_opts(&opt.cmd, config.as_ref()),
       "For udp_desync or netns you need to compile rustpass-dpi with --features udp-desync or with default features"
     );
-    info!("Desync options:\n{:#?}", server.as_ref().unwrap());
-    server.unwrap().start_server();
+    assert!(!servers.is_empty(), "You need to specify tcp subcommand or --config");
+    if !start_servers(servers) { process::exit(1); }
   }
 }
@@ -0,0 +1,202 @@
+use std::net::SocketAddr;
+use std::os::fd::AsRawFd;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context};
+use log::{debug, info, warn};
+use tokio::net::lookup_host;
+use tokio::time::{timeout, timeout_at};
+use tokio_uring::buf::BoundedBuf;
+use tokio_uring::net::TcpStream;
+
+use crate::bypass::{BypassOptions, FAKE_TLS};
+use crate::config::Profile;
+use crate::tls::{chello_with_sni, is_tls_server_hello};
+
+const DEFAULT_PORT: u16 = 443;
+const RESPONSE_BUF_SIZE: usize = 16384;
+const POSITIONS: [&str; 3] = ["1", "s+1", "m"];
+
+/// Desync strategy in config profile syntax
+struct Strategy {
+  toml: String,
+  options: BypassOptions
+}
+
+/// Number of hosts passed with strategy and their average handshake time
+struct Score {
+  strategy: usize,
+  passed: usize,
+  avg_time: Duration
+}
+
+/// Search space: every desync type at every position, fakes with every TTL and md5sig
+fn strategies(fake_ttls: &[u8]) -> Result<Vec<Strategy>, anyhow::Error> {
+  let mut tomls = Vec::new();
+  for pos in POSITIONS {
+    tomls.push(format!("split = [\"{pos}\"]"));
+    tomls.push(format!("disorder = \"{pos}\""));
+    tomls.push(format!("disoob = [\"{pos}\"]"));
+    tomls.push(format!("splitoob = [\"{pos}\"]"));
+    tomls.push(format!("tlsrec = [\"{pos}\"]"));
+    tomls.push(format!("tlsrec = [\"{pos}\"]\nsplit = [\"{pos}\"]"));
+    tomls.extend(fake_ttls.iter().map(|ttl| format!("fake = [\"{pos}\"]\nfake-ttl = {ttl}")));
+    tomls.push(format!("fake = [\"{pos}\"]\nfooling = \"md5sig\""));
+  }
+  tomls.into_iter().map(|toml| {
+    let options = toml::from_str::<Profile>(&toml)?.bypass_options()?;
+    Ok(Strategy{ toml, options })
+  }).collect()
+}
+
+fn parse_host(host: &str) -> Result<(String, u16), anyhow::Error> {
+  if let Ok(addr) = host.parse::<SocketAddr>() { return Ok((addr.ip().to_string(), addr.port())); }
+  match host.rsplit_once(':') {
+    Some((name, port)) if !name.contains(':') => Ok((name.to_owned(), port.parse().with_context(|| format!("wrong port: {host}"))?)),
+    _ => Ok((host.trim_matches(['[', ']']).to_owned(), DEFAULT_PORT))
+  }
+}
+
+/// Checks complete records of server response. Returns true when the handshake flight of the server ended:
+/// encrypted record of TLS 1.3 or ServerHelloDone of TLS 1.2 follows ServerHello. Alert after ServerHello is an error,
+/// because DPI may let ServerHello through and reset or alert the rest
+fn flight_ended(response: &[u8]) -> Result<bool, anyhow::Error> {
+  if response.len() > 5 && !is_tls_server_hello(response) { bail!("no ServerHello, first byte: {:#x}", response[0]); }
+  let mut pos = 0;
+  while pos + 5 <= response.len() {
+    let end = pos + 5 + u16::from_be_bytes([response[pos + 3], response[pos + 4]]) as usize;
+    if end > response.len() { break; }
+    match response[pos] {
+      0x15 => bail!("TLS alert after ServerHello"),
+      0x17 => return Ok(true),
+      0x16 if response[..end].ends_with(&[0x0e, 0, 0, 0]) => return Ok(true),
+      _ => {}
+    }
+    pos = end;
+  }
+  Ok(false)
+}
+
+/// Sends ClientHello with options and reads the handshake flight of the server. Returns time of handshake
+async fn handshake(addr: SocketAddr, chello: &[u8], options: Option<&BypassOptions>, read_timeout: Duration) -> Result<Duration, anyhow::Error> {
+  let start = Instant::now();
+  let stream = timeout(read_timeout, TcpStream::connect(addr)).await.map_err(|_| anyhow!("connect timeout"))??;
+  stream.set_nodelay(true)?;
+  let stream = Rc::new(stream);
+  match options {
+    Some(options) => { options.desync(stream.as_raw_fd(), stream.clone(), chello.to_vec(), chello.len()).await?; }
+    None => { let (res, _) = stream.write_all(chello.to_vec()).await; res?; }
+  }
+  let deadline = Instant::now() + read_timeout;
+  let mut response = Vec::with_capacity(RESPONSE_BUF_SIZE);
+  while !flight_ended(&response)? {
+    if response.len() == response.capacity() { response.reserve(RESPONSE_BUF_SIZE); }
+    let len = response.len();
+    let (res, slice) = timeout_at(deadline.into(), stream.read(response.slice(len..))).await
+      .map_err(|_| anyhow!("timeout after {len} bytes"))?;
+    response = slice.into_inner();
+    if res? == 0 { bail!("connection closed after {len} bytes"); }
+  }
+  Ok(start.elapsed())
+}
+
+/// Handshake time of every strategy for host, None if strategy failed
+async fn probe_host(host: String, strategies: Rc<Vec<Strategy>>, read_timeout: Duration) -> Result<Vec<Option<Duration>>, anyhow::Error> {
+  let (name, port) = parse_host(&host)?;
+  let addr = lookup_host((name.as_str(), port)).await?.next().ok_or_else(|| anyhow!("cannot resolve {name}"))?;
+  let chello = chello_with_sni(&FAKE_TLS, &name)?;
+  match handshake(addr, &chello, None, read_timeout).await {
+    Ok(_) => info!("{host} works without desync"),
+    Err(e) => info!("{host} without desync: {e}")
+  }
+  let mut times = Vec::with_capacity(strategies.len());
+  for strategy in strategies.iter() {
+    let res = handshake(addr, &chello, Some(&strategy.options), read_timeout).await;
+    debug!("{host} with {:?}: {res:?}", strategy.toml);
+    times.push(res.ok());
+  }
+  Ok(times)
+}
+
+/// Probes all strategies for every host concurrently and prints ones which passed for at least one host
+pub fn run(hosts: Vec<String>, fake_ttls: &[u8], read_timeout: f32) -> Result<(), anyhow::Error> {
+  let strategies = Rc::new(strategies(fake_ttls)?);
+  let read_timeout = Duration::from_secs_f32(read_timeout);
+  info!("probing {} strategies for {} hosts", strategies.len(), hosts.len());
+  let results: Vec<Vec<Option<Duration>>> = tokio_uring::start(async {
+    let handles: Vec<_> = hosts.iter()
+      .map(|host| tokio_uring::spawn(probe_host(host.clone(), strategies.clone(), read_timeout)))
+      .collect();
+    let mut results = Vec::with_capacity(handles.len());
+    for (host, handle) in hosts.iter().zip(handles) {
+      match handle.await? {
+        Ok(times) => results.push(times),
+        Err(e) => warn!("{host}: {e:#}")
+      }
+    }
+    Ok::<_, anyhow::Error>(results)
+  })?;
+  let mut scores: Vec<Score> = (0..strategies.len()).filter_map(|strategy| {
+    let times: Vec<Duration> = results.iter().filter_map(|times| times[strategy]).collect();
+    let passed = times.len();
+    (passed > 0).then(|| Score{ strategy, passed, avg_time: times.iter().sum::<Duration>() / passed as u32 })
+  }).collect();
+  scores.sort_by(|a, b| b.passed.cmp(&a.passed).then(a.avg_time.cmp(&b.avg_time)));
+  if scores.is_empty() {
+    println!("# no strategy passed");
+    return Ok(());
+  }
+  for (i, score) in scores.iter().enumerate() {
+    println!("# {}/{} hosts, {} ms", score.passed, results.len(), score.avg_time.as_millis());
+    println!("[profile.probe-{}]\n{}\n", i + 1, strategies[score.strategy].toml);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_probe_hosts() {
+    assert_eq!(parse_host("example.com").unwrap(), ("example.com".into(), 443));
+    assert_eq!(parse_host("example.com:8443").unwrap(), ("example.com".into(), 8443));
+    assert_eq!(parse_host("[::1]:8443").unwrap(), ("::1".into(), 8443));
+    assert_eq!(parse_host("::1").unwrap(), ("::1".into(), 443));
+    assert!(parse_host("example.com:x").is_err());
+  }
+
+  #[test]
+  fn probe_strategies() {
+    let strategies = strategies(&[4, 6]).unwrap();
+    assert_eq!(strategies.len(), POSITIONS.len() * 9);
+    assert!(strategies.iter().all(|s| s.options.at_least_one_option()));
+  }
+
+  fn record(content_type: u8, payload: &[u8]) -> Vec<u8> {
+    let mut record = vec![content_type, 3, 3];
+    record.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    record.extend_from_slice(payload);
+    record
+  }
+
+  #[test]
+  fn server_flight_end() {
+    let server_hello = record(0x16, &[2, 0, 0, 2, 3, 3]);
+    assert!(!flight_ended(&[]).unwrap());
+    assert!(!flight_ended(&server_hello[..4]).unwrap());
+    assert!(!flight_ended(&server_hello).unwrap());
+    // TLS 1.3: change cipher spec and encrypted extensions
+    let tls13 = [server_hello.clone(), record(0x14, &[1]), record(0x17, &[0; 32])].concat();
+    assert!(!flight_ended(&tls13[..tls13.len() - 1]).unwrap());
+    assert!(flight_ended(&tls13).unwrap());
+    // TLS 1.2: certificate and ServerHelloDone
+    let tls12 = [server_hello.clone(), record(0x16, &[11, 0, 0, 1, 0]), record(0x16, &[0x0e, 0, 0, 0])].concat();
+    assert!(!flight_ended(&tls12[..tls12.len() - 4]).unwrap());
+    assert!(flight_ended(&tls12).unwrap());
+    assert!(flight_ended(&[server_hello.clone(), record(0x15, &[2, 40])].concat()).is_err());
+    assert!(flight_ended(&record(0x15, &[2, 40])).is_err());
+    assert!(flight_ended(b"HTTP/1.1 400 Bad Request").is_err());
+  }
+}
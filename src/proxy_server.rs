@@ -1,37 +1,87 @@
-use std::os::fd::AsRawFd;
+use std::cell::Cell;
+use std::future::Future;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 use std::net::{Shutdown, SocketAddr};
 use std::rc::Rc;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use tokio_uring::{self, buf::BoundedBuf};
-use tokio::time::timeout;
-use log::{trace, debug, info, error};
+use anyhow::bail;
+use tokio_uring::{self, buf::BoundedBuf, net::{TcpListener, TcpStream}};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::Notify;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{sleep, sleep_until, timeout};
+use socket2::{Domain, Socket, Type};
+use log::{trace, debug, info, warn, error};
 
-use crate::socks::{Socks4, Socks4Phase, SOCKS4_VERSION};
+use crate::socks::{Socks4, Socks4Phase, Socks5, Socks5Phase, SOCKS4_VERSION, SOCKS5_VERSION, SOCKS5_UDP_ASSOCIATE_COMMAND};
+use crate::http::{host_range, is_http_request};
+use crate::http_connect::{HttpConnect, HttpConnectPhase};
+use crate::tls::{ClientHello, TLS_MAX_RECORD_LEN};
+use crate::transparent::{bind_transparent, original_dst};
+use crate::udp_relay::UdpRelay;
 use crate::bypass::BypassOptions;
+use crate::fallback::{blocked_response, Fallback};
+use crate::splice::{self, splice_one_side, Pipe, SockFd};
+use crate::rules::{Action, Rules};
+use crate::buf_pool::{BufPool, BUF_POOL_SIZE};
+use crate::shutdown;
 
 const BUF_SIZE: usize = 16384;
-pub const BUF_SIZE_STR: &str = concat!(16384);
+const CHELLO_READ_TIMEOUT: Duration = Duration::from_secs(2);
+pub const BUF_SIZE_STR: &str = "16384";
+const UDP_RECV_BUF_SIZE: usize = 65536;
+/// Time to wait for the first response in auto mode if profile has no timeout
+const AUTO_RESPONSE_TIMEOUT: Duration = Duration::from_secs(3);
+/// Time without data after which half-closed connection is closed
+const HALF_CLOSE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Time to wait for running connections after shutdown is requested
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause after failed accept, e.g. when there are no free fds
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Server -> client side of connection. It can be stopped between packets to switch connection to splice
+struct Relay {
+  handle: JoinHandle<Result<bool, anyhow::Error>>,
+  proxy_ready: Rc<AsyncFd<SockFd>>,
+  stop: Rc<Notify>,
+  /// True while the first response is awaited with timeout, splice would drop the timeout
+  waiting: Rc<Cell<bool>>
+}
 
 #[derive(Clone, Debug)]
 pub struct ProxyServer {
-  socks_version: u8,
   pub server_addr: SocketAddr,
+  pub transparent: bool,
   msg_buf_size: usize,
-  pub bypass_options: BypassOptions
-}
-
-fn is_tls_chello(input: &[u8]) -> bool {
-  input.len() > 5 && u16::from_be_bytes([input[0], input[1]]) == 0x1603 && input[5] == 1
+  /// Time without data after which half-closed connection is closed
+  half_close_timeout: Duration,
+  pub bypass_options: BypassOptions,
+  pub rules: Arc<Rules>,
+  pub fallback: Option<Arc<Fallback>>,
+  /// Threads with own io_uring and SO_REUSEPORT listener
+  pub workers: usize,
+  /// Registered buffers of every worker, 0 disables registration
+  pub buf_pool: usize,
+  /// Relay connections with splice after the first request, it takes 4 pipe fds per connection
+  pub splice: bool
 }
 
 impl ProxyServer {
   pub fn new(addr: SocketAddr) -> Self {
     Self{
-      socks_version: SOCKS4_VERSION,
       server_addr: addr,
+      transparent: false,
       msg_buf_size: BUF_SIZE,
-      bypass_options: BypassOptions::new()
+      half_close_timeout: HALF_CLOSE_TIMEOUT,
+      bypass_options: BypassOptions::new(),
+      rules: Arc::new(Rules::default()),
+      fallback: None,
+      workers: 1,
+      buf_pool: BUF_POOL_SIZE,
+      splice: true
     }
   }
 
@@ -39,91 +89,750 @@ impl ProxyServer {
     self.msg_buf_size = size;
   }
 
-  pub async fn proxy_one_side(read_stream: Rc<tokio_uring::net::TcpStream>, write_stream: Rc<tokio_uring::net::TcpStream>,
-                              mut proxy_buf: Vec<u8>, read_timeout: Option<Duration>) -> Result<(), anyhow::Error> {
+  /// Copies data from server to client until EOF or until stop is notified. EOF is passed to client as FIN,
+  /// client still can send data to server. Returns true if it was stopped. waiting is cleared after the first packet
+  #[allow(clippy::too_many_arguments)]
+  pub async fn proxy_one_side(read_stream: Rc<TcpStream>, read_ready: Rc<AsyncFd<SockFd>>, write_stream: Rc<TcpStream>, pool: Rc<BufPool>,
+                              read_timeout: Option<Duration>, stop: Rc<Notify>, waiting: Rc<Cell<bool>>,
+                              active: Rc<Cell<Instant>>) -> Result<bool, anyhow::Error> {
     let mut first_pkt = true;
-    let mut result;
-    let mut npbuf;
     loop {
-      if let Some(r_t) = read_timeout.and_then(|r_t| first_pkt.then_some(r_t)) {
-        if let Ok(ret) = timeout(r_t, read_stream.read(proxy_buf)).await {
-          (result, npbuf) = ret;
-          first_pkt = false;
-        } else {
-          debug!("timeout");
-          break;
+      let wait = splice::wait_data(&read_ready);
+      match read_timeout.filter(|_| first_pkt) {
+        Some(r_t) => tokio::select! {
+          biased;
+          _ = stop.notified() => return Ok(true),
+          res = timeout(r_t, wait) => match res {
+            Ok(res) => res?,
+            Err(_) => {
+              debug!("timeout");
+              shutdown(&read_stream, Shutdown::Both)?;
+              break;
+            }
+          }
+        },
+        None => tokio::select! {
+          biased;
+          _ = stop.notified() => return Ok(true),
+          res = wait => res?
         }
-      } else { (result, npbuf) = read_stream.read(proxy_buf).await; }
-      let proxy_size = result?;
+      }
+      first_pkt = false;
+      waiting.set(false);
+      let (proxy_size, proxy_buf) = pool.read(&read_stream).await?;
       if proxy_size == 0 { break; }
-      proxy_buf = npbuf;
-      let (res, proxy_slice) = write_stream.write(proxy_buf.slice(..proxy_size)).submit().await; res?;
-      proxy_buf = proxy_slice.into_inner();
+      BufPool::write_all(&write_stream, proxy_buf, proxy_size).await?;
+      active.set(Instant::now());
+    }
+    shutdown(&write_stream, Shutdown::Write)?;
+    debug!("server closed, shutdown write to client");
+    Ok(false)
+  }
+
+  /// Reads rest of TLS record, so desync positions are computed against the complete ClientHello.
+  /// Stops on CHELLO_READ_TIMEOUT, EOF or TLS_MAX_RECORD_LEN and returns size of buffered data
+  pub async fn read_full_record(stream: &TcpStream, ready: &AsyncFd<SockFd>, mut buf: Vec<u8>, mut size: usize,
+                                record_len: usize) -> Result<(usize, Vec<u8>), anyhow::Error> {
+    let record_len = record_len.min(TLS_MAX_RECORD_LEN);
+    // buffer bigger than the max record may already hold more
+    if size >= record_len { return Ok((size, buf)); }
+    if buf.len() < record_len { buf.resize(record_len, 0); }
+    while size < record_len {
+      // timeout cancels waiting instead of read, so read data isn't lost
+      if timeout(CHELLO_READ_TIMEOUT, splice::wait_data(ready)).await.is_err() {
+        debug!("timeout while reading ClientHello, got {size} of {record_len} bytes");
+        break;
+      }
+      let (result, slice) = stream.read(buf.slice(size..record_len)).await;
+      buf = slice.into_inner();
+      let n = result?;
+      if n == 0 { break; }
+      size += n;
+      trace!("ClientHello reassembly: {size} of {record_len} bytes");
+    }
+    Ok((size, buf))
+  }
+
+  /// Relays data between client and server desyncing TLS ClientHello and the first http request.
+  /// dst_host is domain requested by client, it is used for rules if there is no SNI or Host header.
+  /// first_data is data which client sent together with proxy request, it is relayed before data of the socket
+  pub async fn socks_proxy(self, pool: Rc<BufPool>, client_stream: TcpStream, client_ready: Rc<AsyncFd<SockFd>>, proxy_stream: TcpStream,
+                           mut dst_host: Option<String>, first_data: Vec<u8>) -> Result<(), anyhow::Error> {
+    let mut proxy_stream_rc = Rc::new(proxy_stream);
+    let client_stream_rc = Rc::new(client_stream);
+    let active = Rc::new(Cell::new(Instant::now()));
+    let mut proxy_ready = splice::register(proxy_stream_rc.as_raw_fd())?;
+    // server -> client side starts after rules selected options of the first request, so their timeout is used.
+    // In auto mode it starts after the first response, so the first request can be sent again
+    let mut relay: Option<Relay> = None;
+    // None if connection is relayed without desync
+    let mut bypass_options = Some(self.bypass_options.clone());
+    let mut first_request = true;
+    let mut try_splice = self.splice;
+    // relay from server finished, its handle can't be awaited again
+    let mut server_closed = false;
+    let mut first_data = (!first_data.is_empty()).then_some(first_data);
+    loop {
+      // nothing is desynced after the first request
+      // with timeout of the first response the switch is retried after the next client data
+      if !first_request && try_splice {
+        if let Some(server_side) = relay.take_if(|relay| !relay.waiting.get() && !server_closed) {
+          relay = ProxyServer::splice_relay(&client_stream_rc, &proxy_stream_rc, &client_ready, server_side, &active,
+                                            self.half_close_timeout).await?;
+          if relay.is_none() { return Ok(()); }
+          try_splice = false;
+        }
+      }
+      let (mut client_buf, mut client_size) = match first_data.take() {
+        Some(data) => {
+          let n = data.len();
+          (data, n)
+        }
+        None => {
+          match &mut relay {
+            None => tokio::select! {
+              res = splice::wait_data(&client_ready) => res?,
+              res = splice::wait_data(&proxy_ready) => {
+                res?;
+                // server speaks first, its data is relayed without waiting for request of client
+                relay = Some(self.relay_from_server(&pool, &proxy_stream_rc, &proxy_ready, &client_stream_rc, None, &active)?);
+                continue;
+              }
+            },
+            Some(server_side) if !server_closed => tokio::select! {
+              res = splice::wait_data(&client_ready) => res?,
+              res = &mut server_side.handle => {
+                res??;
+                server_closed = true;
+                continue;
+              }
+            },
+            // server closed first, idle client gets EOF of read below
+            Some(_) => tokio::select! {
+              res = splice::wait_data(&client_ready) => res?,
+              _ = close_when_idle(&client_stream_rc, &active, self.half_close_timeout) => {}
+            }
+          }
+          let (n, buf) = pool.read(&client_stream_rc).await?;
+          if n == 0 { break; }
+          active.set(Instant::now());
+          // only ClientHello and the first request can be desynced, other data is written from the pool buffer
+          if !first_request && ClientHello::parse(&buf[..n]).is_none() {
+            BufPool::write_all(&proxy_stream_rc, buf, n).await?;
+            continue;
+          }
+          (buf[..n].to_vec(), n)
+        }
+      };
+      let mut chello = ClientHello::parse(&client_buf[..client_size]);
+      let proxy_fd = proxy_stream_rc.as_raw_fd();
+      if chello.as_ref().is_some_and(|chello| chello.full_record_len() > client_size) {
+        let record_len = chello.unwrap().full_record_len();
+        (client_size, client_buf) = ProxyServer::read_full_record(&client_stream_rc, &client_ready, client_buf, client_size, record_len).await?;
+        chello = ClientHello::parse(&client_buf[..client_size]);
+      }
+      let http = chello.is_none() && first_request && is_http_request(&client_buf[..client_size]);
+      let mut host = match &chello {
+        Some(chello) => chello.sni.as_ref().map(|sni| sni.host.clone()),
+        None if http => host_range(&client_buf[..client_size]).map(|host| String::from_utf8_lossy(&client_buf[host]).into_owned()),
+        None => None
+      };
+      if let Some(chello) = &chello {
+        debug!("ClientHello sni: {:?}, alpn: {:?}, supported versions: {:x?}, record len: {}, truncated: {}",
+          host, chello.alpn, chello.supported_versions, chello.full_record_len(), chello.truncated);
+      } else if http {
+        debug!("http request to {host:?}");
+      }
+      if first_request {
+        host = host.or(dst_host.take());
+      }
+      if first_request && !self.rules.is_empty() {
+        match self.rules.select(host.as_deref(), peer_addr(proxy_fd).map(|addr| addr.ip())) {
+          Some(Action::NoDesync) => {
+            debug!("{host:?} matches rule without desync");
+            bypass_options = None;
+          }
+          Some(Action::Desync(options)) => {
+            debug!("{host:?} matches rule with desync");
+            bypass_options = Some((**options).clone());
+          }
+          None => {}
+        }
+      }
+      if relay.is_none() {
+        if let (Some(fallback), Some(options)) = (&self.fallback, &bypass_options) {
+          if chello.is_some() || http {
+            // without host the profile isn't cached, hosts behind the same CDN address may need different profiles
+            let worked;
+            (proxy_stream_rc, worked) = self.auto_desync(fallback, host.as_deref(), options, proxy_stream_rc, &client_stream_rc,
+                                                         &client_buf[..client_size], chello.is_some()).await?;
+            proxy_ready = splice::register(proxy_stream_rc.as_raw_fd())?;
+            relay = Some(self.relay_from_server(&pool, &proxy_stream_rc, &proxy_ready, &client_stream_rc, worked.timeout, &active)?);
+            bypass_options = Some(worked);
+            first_request = false;
+            continue;
+          }
+        }
+        // options of matched rule or listener, connection without desync has no timeout
+        let read_timeout = bypass_options.as_ref().and_then(|options| options.timeout);
+        relay = Some(self.relay_from_server(&pool, &proxy_stream_rc, &proxy_ready, &client_stream_rc, read_timeout, &active)?);
+      }
+      match &bypass_options {
+        Some(bypass_options) if chello.is_some() || http => {
+          bypass_options.desync(proxy_fd, proxy_stream_rc.clone(), client_buf, client_size).await?;
+        }
+        _ => {
+          let (res, _) = proxy_stream_rc.write_all(client_buf.slice(..client_size)).await; res?;
+        }
+      }
+      first_request = false;
+    }
+    shutdown(&proxy_stream_rc, Shutdown::Write)?;
+    trace!("client closed, shutdown write to server");
+    if server_closed { return Ok(()); }
+    // response of server is still relayed to client, also if client closed before the first request
+    let mut relay = match relay {
+      Some(relay) => relay,
+      None => self.relay_from_server(&pool, &proxy_stream_rc, &proxy_ready, &client_stream_rc, None, &active)?
+    };
+    tokio::select! {
+      res = &mut relay.handle => { res??; }
+      _ = close_when_idle(&proxy_stream_rc, &active, self.half_close_timeout) => { relay.handle.await??; }
     }
-    read_stream.shutdown(Shutdown::Both)?;
-    debug!("shutdown with client");
     Ok(())
   }
 
-  pub async fn socks_proxy(self, socks4: Socks4) -> Result<(), anyhow::Error> {
-    let mut client_buf = vec![0u8; self.msg_buf_size];
-    let proxy_buf = vec![0u8; self.msg_buf_size];
-    let mut client_size;
-    let proxy_stream = socks4.proxy_stream.unwrap();
-    let proxy_stream_rc = Rc::new(proxy_stream);
-    let client_stream_rc = Rc::new(socks4.client_stream);
-    let proxy_stream_rc1 = proxy_stream_rc.clone();
-    let client_stream_rc1 = client_stream_rc.clone();
+  fn relay_from_server(&self, pool: &Rc<BufPool>, proxy_stream: &Rc<TcpStream>, proxy_ready: &Rc<AsyncFd<SockFd>>,
+                       client_stream: &Rc<TcpStream>, read_timeout: Option<Duration>, active: &Rc<Cell<Instant>>) -> io::Result<Relay> {
+    let proxy_ready = proxy_ready.clone();
+    let stop = Rc::new(Notify::new());
+    let waiting = Rc::new(Cell::new(read_timeout.is_some()));
+    let handle = tokio_uring::spawn(ProxyServer::proxy_one_side(proxy_stream.clone(), proxy_ready.clone(), client_stream.clone(),
+                                                                pool.clone(), read_timeout, stop.clone(), waiting.clone(),
+                                                                active.clone()));
+    Ok(Relay{ handle, proxy_ready, stop, waiting })
+  }
+
+  /// Stops copying relay from server and relays both sides with splice till EOF of both.
+  /// Returns the relay back if pipes can't be created
+  async fn splice_relay(client_stream: &Rc<TcpStream>, proxy_stream: &Rc<TcpStream>, client_ready: &Rc<AsyncFd<SockFd>>,
+                        relay: Relay, active: &Cell<Instant>, idle_timeout: Duration) -> Result<Option<Relay>, anyhow::Error> {
+    let (to_proxy, to_client) = match Pipe::new().and_then(|to_proxy| Ok((to_proxy, Pipe::new()?))) {
+      Ok(pipes) => pipes,
+      Err(e) => {
+        debug!("can't create pipes for splice: {e}");
+        return Ok(Some(relay));
+      }
+    };
+    relay.stop.notify_one();
+    // false if server side is already closed
+    let stopped = relay.handle.await??;
+    // sockets aren't used by io_uring anymore
+    splice::set_nonblocking(client_stream.as_raw_fd())?;
+    splice::set_nonblocking(proxy_stream.as_raw_fd())?;
+    trace!("switched to splice");
+    let to_server = async {
+      splice_one_side(client_ready.clone(), relay.proxy_ready.clone(), to_proxy, active).await?;
+      shutdown(proxy_stream, Shutdown::Write)?;
+      trace!("client closed, shutdown write to server");
+      Ok(())
+    };
+    let to_client = async {
+      // server side was already closed by relay if it wasn't stopped
+      if stopped {
+        splice_one_side(relay.proxy_ready.clone(), client_ready.clone(), to_client, active).await?;
+        shutdown(client_stream, Shutdown::Write)?;
+        debug!("server closed, shutdown write to client");
+      }
+      Ok(())
+    };
+    relay_both(to_server, client_stream, to_client, proxy_stream, active, idle_timeout).await?;
+    Ok(None)
+  }
+
+  /// Sends the first request with listener options and profiles of auto mode until the server answers without failure.
+  /// Every next attempt uses new connection. Response of the server is written to client.
+  /// Returns stream to the server and options which worked
+  #[allow(clippy::too_many_arguments)]
+  async fn auto_desync(&self, fallback: &Fallback, host: Option<&str>, options: &BypassOptions, mut proxy_stream: Rc<TcpStream>,
+                       client_stream: &TcpStream, request: &[u8], tls: bool) -> Result<(Rc<TcpStream>, BypassOptions), anyhow::Error> {
+    let Some(dst) = peer_addr(proxy_stream.as_raw_fd()) else { bail!("auto: unknown destination of {host:?}"); };
+    let host_name = host.unwrap_or("unknown host");
+    let mut response = vec![0u8; self.msg_buf_size];
+    for (i, attempt) in fallback.attempts(host).into_iter().enumerate() {
+      let attempt_options = fallback.options(attempt).unwrap_or(options);
+      if i > 0 {
+        let _ = proxy_stream.shutdown(Shutdown::Both);
+        proxy_stream = Rc::new(TcpStream::connect(dst).await?);
+        proxy_stream.set_nodelay(true)?;
+      }
+      let mut buf = vec![0u8; request.len()];
+      buf.copy_from_slice(request);
+      let sent = attempt_options.desync(proxy_stream.as_raw_fd(), proxy_stream.clone(), buf, request.len()).await;
+      let read_timeout = attempt_options.timeout.unwrap_or(AUTO_RESPONSE_TIMEOUT);
+      let failure = match sent {
+        Err(e) => e.to_string(),
+        Ok(_) => {
+          // timeout cancels waiting instead of read, so response buffer is kept
+          let proxy_ready = splice::register(proxy_stream.as_raw_fd())?;
+          match timeout(read_timeout, splice::wait_data(&proxy_ready)).await {
+            Err(_) => "timeout".to_owned(),
+            Ok(Err(e)) => e.to_string(),
+            Ok(Ok(())) => {
+              let (result, nbuf) = proxy_stream.read(response).await;
+              response = nbuf;
+              match result {
+                Err(e) => e.to_string(),
+                Ok(n) => match blocked_response(&response[..n], tls) {
+                  Some(reason) => reason.to_owned(),
+                  None => {
+                    info!("auto: {host_name} works with {}", fallback.name(attempt));
+                    if let Some(host) = host { fallback.save(host, attempt); }
+                    let (res, _) = client_stream.write(response.slice(..n)).submit().await; res?;
+                    return Ok((proxy_stream, attempt_options.clone()));
+                  }
+                }
+              }
+            }
+          }
+        }
+      };
+      debug!("auto: {host_name} failed with {}: {failure}", fallback.name(attempt));
+    }
+    bail!("auto: all profiles failed for {host_name}")
+  }
+
+  /// Relays udp datagrams while client keeps tcp connection of udp associate request
+  pub async fn udp_associate_proxy(client_stream: TcpStream, relay: UdpRelay, buf: Vec<u8>) -> Result<(), anyhow::Error> {
+    let relay_buf = vec![0u8; UDP_RECV_BUF_SIZE];
     let res = tokio_uring::spawn(async move {
-      ProxyServer::proxy_one_side(proxy_stream_rc1, client_stream_rc1, proxy_buf, self.bypass_options.timeout).await
+      let _ = relay.run(relay_buf).await.inspect_err(|e| error!("udp relay: {e:?}"));
     });
+    let mut buf = buf;
     loop {
-      let (result, nbuf) = client_stream_rc.read(client_buf).await;
-      client_size = result?;
-      if client_size == 0 { break; }
-      client_buf = nbuf;
-      let proxy_fd = proxy_stream_rc.as_raw_fd();
-      if is_tls_chello(&client_buf[..client_size]) {
-        client_buf = self.bypass_options.desync(proxy_fd, proxy_stream_rc.clone(), client_buf, client_size).await?;
-      } else {
-        let (res, slice) = proxy_stream_rc.write(client_buf.slice(..client_size)).submit().await; res?;
-        client_buf = slice.into_inner();
-      }
+      let (result, nbuf) = client_stream.read(buf).await;
+      buf = nbuf;
+      if result? == 0 { break; }
     }
-    proxy_stream_rc.shutdown(Shutdown::Both)?;
-    trace!("shutdown with proxy");
+    debug!("udp associate connection closed");
     res.abort();
     Ok(())
   }
 
-  pub async fn handle_client(self, stream: tokio_uring::net::TcpStream) -> Result<(), anyhow::Error> {
+  pub async fn handle_transparent_client(self, pool: Rc<BufPool>, stream: TcpStream) -> Result<(), anyhow::Error> {
+    let dst = original_dst(&stream, self.server_addr)?;
+    debug!("transparent connection to {dst}");
+    let client_ready = splice::register(stream.as_raw_fd())?;
+    let proxy_stream = TcpStream::connect(dst).await?;
+    proxy_stream.set_nodelay(true)?;
+    self.socks_proxy(pool, stream, client_ready, proxy_stream, None, Vec::new()).await
+  }
+
+  pub async fn handle_client(self, pool: Rc<BufPool>, stream: TcpStream) -> Result<(), anyhow::Error> {
+    let client_ready = splice::register(stream.as_raw_fd())?;
+    // buffer isn't allocated for clients which connected but didn't send anything yet
+    splice::wait_data(&client_ready).await?;
     let first_input = vec![0u8; self.msg_buf_size];
-    let (result, first_input) = stream.read(first_input).await;
+    let (result, mut first_input) = stream.read(first_input).await;
     let n = result?;
     if n == 0 {
       debug!("exiting because n=0");
       return Ok(());
     }
-    let mut socks4 = Socks4::is_connect_req(&first_input[..n], stream)?;
-    socks4.connect_to_dst(&first_input[..n]).await?;
-    if let Some(pr_stream) = socks4.proxy_stream.as_ref() { pr_stream.set_nodelay(true)?; }
-    socks4.phase = Socks4Phase::Proxing;
-    self.socks_proxy(socks4).await?;
+    let (client_stream, proxy_stream, dst_host, first_data) = match first_input[0] {
+      SOCKS4_VERSION => {
+        let mut socks4 = Socks4::is_connect_req(&first_input[..n], stream)?;
+        socks4.connect_to_dst(&first_input[..n]).await?;
+        socks4.phase = Socks4Phase::Proxing;
+        let dst_host = socks4.proxy_addr.domain().map(str::to_owned);
+        let first_data = first_input[socks4.req_len..n].to_vec();
+        (socks4.client_stream, socks4.proxy_stream.unwrap(), dst_host, first_data)
+      }
+      SOCKS5_VERSION => {
+        let mut socks5 = Socks5::is_method_req(&first_input[..n], stream)?;
+        let (mut first_input, method_len, n) = socks5.read_method_req(std::mem::take(&mut first_input), n).await?;
+        socks5.reply_method(&first_input[..method_len]).await?;
+        // the request could be sent with the method request
+        first_input.copy_within(method_len..n, 0);
+        let (first_input, rest) = socks5.read_req(first_input, n - method_len).await?;
+        if socks5.command == SOCKS5_UDP_ASSOCIATE_COMMAND {
+          let (socket, client_addr) = socks5.udp_associate().await?;
+          socks5.phase = Socks5Phase::Proxing;
+          let relay = UdpRelay::new(socket, client_addr, self.bypass_options.fake_ttl,
+                                    self.bypass_options.udp_fake_payload.clone());
+          return ProxyServer::udp_associate_proxy(socks5.client_stream, relay, first_input).await;
+        }
+        socks5.connect_to_dst().await?;
+        socks5.phase = Socks5Phase::Proxing;
+        let dst_host = socks5.proxy_addr.as_ref().and_then(|addr| addr.domain()).map(str::to_owned);
+        (socks5.client_stream, socks5.proxy_stream.unwrap(), dst_host, first_input[rest].to_vec())
+      }
+      _ if HttpConnect::is_connect_req(&first_input[..n]) => {
+        let (mut http, first_data) = HttpConnect::read_connect_req(std::mem::take(&mut first_input), n, stream).await?;
+        http.connect_to_dst().await?;
+        http.phase = HttpConnectPhase::Proxing;
+        let dst_host = http.proxy_addr.domain().map(str::to_owned);
+        (http.client_stream, http.proxy_stream.unwrap(), dst_host, first_data)
+      }
+      ver => bail!("unsupported proxy protocol, first byte: {ver}")
+    };
+    drop(first_input);
+    proxy_stream.set_nodelay(true)?;
+    self.socks_proxy(pool, client_stream, client_ready, proxy_stream, dst_host, first_data).await?;
     Ok(())
   }
 
-  pub fn start_server(self) {
-    assert_eq!(self.socks_version, 4);
+  /// Accepts connections until shutdown is requested, then waits for running connections at most DRAIN_TIMEOUT
+  pub fn start_server(self) -> Result<(), anyhow::Error> {
     tokio_uring::start(async {
-      let listener = tokio_uring::net::TcpListener::bind(self.server_addr).unwrap();
+      let reuse_port = self.workers > 1;
+      let listener = if self.transparent { bind_transparent(self.server_addr, reuse_port)? }
+        else { bind(self.server_addr, reuse_port)? };
+      let pool = Rc::new(BufPool::new(self.buf_pool, self.msg_buf_size));
+      let mut connections = JoinSet::new();
       loop {
-        let (stream, socket_addr) = listener.accept().await.unwrap();
+        let (stream, socket_addr) = tokio::select! {
+          _ = shutdown::requested() => break,
+          res = listener.accept() => match res {
+            Ok(accepted) => accepted,
+            Err(e) => {
+              error!("accept on {}: {e}", self.server_addr);
+              sleep(ACCEPT_ERROR_DELAY).await;
+              continue;
+            }
+          }
+        };
+        // finished connections are removed, so the set doesn't grow
+        while connections.try_join_next().is_some() {}
         let proxy_server = self.clone();
+        let pool = pool.clone();
         info!("Accepted connection from: {socket_addr}");
-        tokio_uring::spawn(async move {
-          let _ = proxy_server.handle_client(stream).await.inspect_err(|e| error!("{e:?}"));
+        connections.spawn_local(async move {
+          let res = if proxy_server.transparent { proxy_server.handle_transparent_client(pool, stream).await }
+            else { proxy_server.handle_client(pool, stream).await };
+          let _ = res.inspect_err(|e| error!("{e:?}"));
+        });
+      }
+      drop(listener);
+      if !connections.is_empty() {
+        info!("{}: waiting for {} connections", self.server_addr, connections.len());
+        if timeout(DRAIN_TIMEOUT, async { while connections.join_next().await.is_some() {} }).await.is_err() {
+          warn!("{}: {} connections are aborted after {DRAIN_TIMEOUT:?}", self.server_addr, connections.len());
+        }
+      }
+      let (reads, exhausted) = pool.stats();
+      info!("{}: buffer pool was exhausted {exhausted} times of {reads} reads", self.server_addr);
+      Ok(())
+    })
+  }
+}
+
+/// With reuse_port every worker binds its own listener to the same addr and kernel spreads connections between them
+fn bind(addr: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+  let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+  socket.set_reuse_address(true)?;
+  socket.set_reuse_port(reuse_port)?;
+  socket.bind(&addr.into())?;
+  socket.listen(1024)?;
+  Ok(TcpListener::from_std(socket.into()))
+}
+
+/// Shutdown which ignores connection already reset by peer
+fn shutdown(stream: &TcpStream, how: Shutdown) -> io::Result<()> {
+  match stream.shutdown(how) {
+    Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+    res => res
+  }
+}
+
+/// Closes reading side of stream after idle_timeout without data, so relay reading it gets EOF
+async fn close_when_idle(stream: &TcpStream, active: &Cell<Instant>, idle_timeout: Duration) {
+  loop {
+    let deadline = active.get() + idle_timeout;
+    if deadline <= Instant::now() { break; }
+    sleep_until(deadline.into()).await;
+  }
+  debug!("half-closed connection is idle, closing");
+  let _ = stream.shutdown(Shutdown::Read);
+}
+
+/// Waits for EOF of both directions, every direction passes its EOF on as FIN.
+/// After one direction is done, the other one is ended by close_when_idle of its source
+async fn relay_both(to_server: impl Future<Output = io::Result<()>>, client_stream: &TcpStream,
+                    to_client: impl Future<Output = io::Result<()>>, proxy_stream: &TcpStream, active: &Cell<Instant>,
+                    idle_timeout: Duration) -> io::Result<()> {
+  tokio::pin!(to_server, to_client);
+  let (mut to_server_done, mut to_client_done, mut idle_closed) = (false, false, false);
+  while !(to_server_done && to_client_done) {
+    let rest_source = if to_server_done { proxy_stream } else { client_stream };
+    tokio::select! {
+      res = &mut to_server, if !to_server_done => { res?; to_server_done = true; }
+      res = &mut to_client, if !to_client_done => { res?; to_client_done = true; }
+      _ = close_when_idle(rest_source, active, idle_timeout), if to_server_done != to_client_done && !idle_closed => { idle_closed = true; }
+    }
+  }
+  Ok(())
+}
+
+fn peer_addr(fd: RawFd) -> Option<SocketAddr> {
+  let sock = unsafe { Socket::from_raw_fd(fd) };
+  let peer = sock.peer_addr();
+  let _ = sock.into_raw_fd();
+  peer.ok()?.as_socket()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::{Read, Write};
+  use std::net::TcpListener;
+  use std::thread;
+
+  use super::*;
+  use crate::bypass::FAKE_TLS;
+
+  #[test]
+  fn reassemble_chello_from_many_reads() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = thread::spawn(move || {
+      let (mut stream, _) = listener.accept().unwrap();
+      stream.set_nodelay(true).unwrap();
+      for chunk in FAKE_TLS.chunks(200) {
+        stream.write_all(chunk).unwrap();
+        thread::sleep(Duration::from_millis(20));
+      }
+    });
+    tokio_uring::start(async move {
+      let stream = TcpStream::connect(addr).await.unwrap();
+      let (result, buf) = stream.read(vec![0u8; 200]).await;
+      let size = result.unwrap();
+      let chello = ClientHello::parse(&buf[..size]).unwrap();
+      let ready = splice::register(stream.as_raw_fd()).unwrap();
+      let (size, buf) = ProxyServer::read_full_record(&stream, &ready, buf, size, chello.full_record_len()).await.unwrap();
+      assert_eq!(&buf[..size], FAKE_TLS);
+      assert!(!ClientHello::parse(&buf[..size]).unwrap().truncated);
+    });
+    client.join().unwrap();
+  }
+
+  #[test]
+  fn read_record_into_buffer_bigger_than_max_record() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio_uring::start(async move {
+      let stream = TcpStream::connect(addr).await.unwrap();
+      let _peer = listener.accept().unwrap();
+      let ready = splice::register(stream.as_raw_fd()).unwrap();
+      // with --buf-size above TLS_MAX_RECORD_LEN one read may hold more than the max record
+      let mut buf = vec![0u8; TLS_MAX_RECORD_LEN + 100];
+      buf[..5].copy_from_slice(&[0x16, 3, 1, 0xff, 0xff]);
+      let size = buf.len();
+      let start = Instant::now();
+      let (read_size, read_buf) = ProxyServer::read_full_record(&stream, &ready, buf.clone(), size, 0xffff + 5).await.unwrap();
+      assert_eq!((read_size, read_buf), (size, buf));
+      assert!(start.elapsed() < CHELLO_READ_TIMEOUT);
+    });
+  }
+
+  #[test]
+  fn relay_response_after_client_half_close() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let server = thread::spawn(move || {
+      let (mut stream, _) = server.accept().unwrap();
+      let mut request = Vec::new();
+      stream.read_to_end(&mut request).unwrap();
+      stream.write_all(&request.repeat(2)).unwrap();
+    });
+    let client = thread::spawn(move || {
+      let mut stream = std::net::TcpStream::connect(proxy_addr).unwrap();
+      stream.write_all(b"request").unwrap();
+      stream.shutdown(Shutdown::Write).unwrap();
+      let mut response = Vec::new();
+      stream.read_to_end(&mut response).unwrap();
+      response
+    });
+    tokio_uring::start(async {
+      let (client_stream, _) = tokio_uring::net::TcpListener::from_std(proxy).accept().await.unwrap();
+      let client_ready = splice::register(client_stream.as_raw_fd()).unwrap();
+      let proxy_stream = TcpStream::connect(server_addr).await.unwrap();
+      let pool = Rc::new(BufPool::new(4, BUF_SIZE));
+      ProxyServer::new(proxy_addr).socks_proxy(pool, client_stream, client_ready, proxy_stream, None, Vec::new()).await.unwrap();
+    });
+    server.join().unwrap();
+    assert_eq!(client.join().unwrap(), b"requestrequest");
+  }
+
+  #[test]
+  fn close_idle_client_after_server_close() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let server = thread::spawn(move || {
+      let (mut stream, _) = server.accept().unwrap();
+      let mut request = [0u8; 7];
+      stream.read_exact(&mut request).unwrap();
+      stream.write_all(&request).unwrap();
+    });
+    // client gets the response and FIN, but doesn't close its side
+    let (closed_tx, closed_rx) = std::sync::mpsc::channel::<()>();
+    let client = thread::spawn(move || {
+      let mut stream = std::net::TcpStream::connect(proxy_addr).unwrap();
+      stream.write_all(b"request").unwrap();
+      let mut response = Vec::new();
+      stream.read_to_end(&mut response).unwrap();
+      let _ = closed_rx.recv();
+      response
+    });
+    tokio_uring::start(async {
+      let (client_stream, _) = tokio_uring::net::TcpListener::from_std(proxy).accept().await.unwrap();
+      let client_ready = splice::register(client_stream.as_raw_fd()).unwrap();
+      let proxy_stream = TcpStream::connect(server_addr).await.unwrap();
+      let pool = Rc::new(BufPool::new(4, BUF_SIZE));
+      let mut proxy_server = ProxyServer::new(proxy_addr);
+      proxy_server.splice = false;
+      proxy_server.half_close_timeout = Duration::from_millis(200);
+      let relay = proxy_server.socks_proxy(pool, client_stream, client_ready, proxy_stream, None, Vec::new());
+      timeout(Duration::from_secs(5), relay).await.expect("idle client isn't closed").unwrap();
+    });
+    closed_tx.send(()).unwrap();
+    server.join().unwrap();
+    assert_eq!(client.join().unwrap(), b"request");
+  }
+
+  #[test]
+  fn timeout_of_rule_profile() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    // server reads the request and doesn't answer
+    let server = thread::spawn(move || {
+      let (mut stream, _) = server.accept().unwrap();
+      let mut request = Vec::new();
+      let _ = stream.read_to_end(&mut request);
+    });
+    let client = thread::spawn(move || {
+      let mut stream = std::net::TcpStream::connect(proxy_addr).unwrap();
+      stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+      stream.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+      let start = Instant::now();
+      let mut response = Vec::new();
+      stream.read_to_end(&mut response).unwrap();
+      start.elapsed()
+    });
+    let mut options = BypassOptions::new();
+    options.append_options(vec![crate::bypass::SplitPosition{ pos: "1".parse().unwrap(), desync_type: crate::bypass::DesyncType::Split }]);
+    options.timeout = Some(Duration::from_millis(200));
+    let mut rule = crate::rules::Rule::new(Action::Desync(Box::new(options)));
+    rule.nets = vec!["127.0.0.1".parse().unwrap()];
+    tokio_uring::start(async {
+      let (client_stream, _) = tokio_uring::net::TcpListener::from_std(proxy).accept().await.unwrap();
+      let client_ready = splice::register(client_stream.as_raw_fd()).unwrap();
+      let proxy_stream = TcpStream::connect(server_addr).await.unwrap();
+      let pool = Rc::new(BufPool::new(4, BUF_SIZE));
+      let mut proxy_server = ProxyServer::new(proxy_addr);
+      proxy_server.rules = Arc::new(Rules(vec![rule]));
+      proxy_server.socks_proxy(pool, client_stream, client_ready, proxy_stream, None, Vec::new()).await.unwrap();
+    });
+    // listener options have no timeout, the connection is closed by timeout of the rule
+    assert!(client.join().unwrap() < Duration::from_secs(2));
+    server.join().unwrap();
+  }
+
+  #[test]
+  fn switch_to_splice_after_first_request() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let data: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+    let server = thread::spawn(move || {
+      let (mut stream, _) = server.accept().unwrap();
+      let mut buf = [0u8; 65536];
+      loop {
+        let n = stream.read(&mut buf).unwrap();
+        if n == 0 { break; }
+        stream.write_all(&buf[..n]).unwrap();
+      }
+    });
+    let client = thread::spawn({
+      let data = data.clone();
+      move || {
+        let mut stream = std::net::TcpStream::connect(proxy_addr).unwrap();
+        stream.write_all(b"first").unwrap();
+        let mut first = [0u8; 5];
+        stream.read_exact(&mut first).unwrap();
+        assert_eq!(&first, b"first");
+        // the rest is relayed by splice in both directions
+        let mut writer = stream.try_clone().unwrap();
+        let writer = thread::spawn(move || {
+          writer.write_all(&data).unwrap();
+          writer.shutdown(Shutdown::Write).unwrap();
         });
+        let mut echo = Vec::new();
+        stream.read_to_end(&mut echo).unwrap();
+        writer.join().unwrap();
+        echo
       }
     });
+    tokio_uring::start(async {
+      let (client_stream, _) = tokio_uring::net::TcpListener::from_std(proxy).accept().await.unwrap();
+      let client_ready = splice::register(client_stream.as_raw_fd()).unwrap();
+      let proxy_stream = TcpStream::connect(server_addr).await.unwrap();
+      let pool = Rc::new(BufPool::new(4, BUF_SIZE));
+      ProxyServer::new(proxy_addr).socks_proxy(pool, client_stream, client_ready.clone(), proxy_stream, None, Vec::new()).await.unwrap();
+      // O_NONBLOCK is set only when connection is switched to splice
+      let flags = unsafe { libc::fcntl(client_ready.as_raw_fd(), libc::F_GETFL) };
+      assert_ne!(flags & libc::O_NONBLOCK, 0);
+    });
+    server.join().unwrap();
+    assert!(client.join().unwrap() == data);
+  }
+
+  #[test]
+  fn auto_mode_uses_next_profile_after_reset() {
+    const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let server = thread::spawn(move || {
+      let mut request = [0u8; REQUEST.len()];
+      // the first attempt is reset
+      let (mut stream, _) = server.accept().unwrap();
+      stream.read_exact(&mut request).unwrap();
+      socket2::SockRef::from(&stream).set_linger(Some(Duration::ZERO)).unwrap();
+      drop(stream);
+      let (mut stream, _) = server.accept().unwrap();
+      stream.read_exact(&mut request).unwrap();
+      assert_eq!(request, REQUEST);
+      stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
+      assert_eq!(stream.read(&mut request).unwrap(), 0);
+    });
+    let client = thread::spawn(move || {
+      let mut stream = std::net::TcpStream::connect(proxy_addr).unwrap();
+      stream.write_all(REQUEST).unwrap();
+      let mut response = [0u8; 19];
+      stream.read_exact(&mut response).unwrap();
+      stream.shutdown(Shutdown::Write).unwrap();
+      response
+    });
+    let fallback = Arc::new(Fallback::new(vec![("next".into(), BypassOptions::new())]));
+    tokio_uring::start(async {
+      let (client_stream, _) = tokio_uring::net::TcpListener::from_std(proxy).accept().await.unwrap();
+      let client_ready = splice::register(client_stream.as_raw_fd()).unwrap();
+      let proxy_stream = TcpStream::connect(server_addr).await.unwrap();
+      let pool = Rc::new(BufPool::new(4, BUF_SIZE));
+      let mut proxy_server = ProxyServer::new(proxy_addr);
+      proxy_server.fallback = Some(fallback.clone());
+      proxy_server.socks_proxy(pool, client_stream, client_ready, proxy_stream, None, Vec::new()).await.unwrap();
+    });
+    server.join().unwrap();
+    assert_eq!(&client.join().unwrap(), b"HTTP/1.1 200 OK\r\n\r\n");
+    assert_eq!(fallback.attempts(Some("example.com"))[0], Some(0));
   }
 }
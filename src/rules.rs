@@ -0,0 +1,188 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+
+use crate::bypass::BypassOptions;
+
+/// IP network in CIDR notation, single address is a network with full prefix
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNet {
+  addr: IpAddr,
+  prefix: u8
+}
+
+impl IpNet {
+  pub fn contains(&self, ip: IpAddr) -> bool {
+    let ip = match ip {
+      IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+      ip => ip
+    };
+    match (self.addr, ip) {
+      (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_eq(&net.octets(), &ip.octets(), self.prefix),
+      (IpAddr::V6(net), IpAddr::V6(ip)) => prefix_eq(&net.octets(), &ip.octets(), self.prefix),
+      _ => false
+    }
+  }
+}
+
+fn prefix_eq(net: &[u8], ip: &[u8], prefix: u8) -> bool {
+  let (bytes, bits) = (prefix as usize / 8, prefix % 8);
+  net[..bytes] == ip[..bytes] && (bits == 0 || (net[bytes] ^ ip[bytes]) >> (8 - bits) == 0)
+}
+
+impl FromStr for IpNet {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (addr, prefix) = s.split_once('/').map_or((s, None), |(addr, prefix)| (addr, Some(prefix)));
+    let addr: IpAddr = addr.parse().with_context(|| format!("wrong ip: {s}"))?;
+    let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+      Some(prefix) => prefix.parse::<u8>().with_context(|| format!("wrong prefix: {s}"))?,
+      None => max_prefix
+    };
+    if prefix > max_prefix { bail!("wrong prefix: {s}"); }
+    Ok(Self{ addr, prefix })
+  }
+}
+
+/// Domain pattern. Pattern without '*' matches the domain and all its subdomains,
+/// pattern with '*' matches the whole host name, '*' matches any sequence of chars
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DomainPattern {
+  Suffix(String),
+  Wildcard(String)
+}
+
+impl DomainPattern {
+  pub fn matches(&self, host: &str) -> bool {
+    match self {
+      Self::Suffix(domain) => host.strip_suffix(domain.as_str())
+        .is_some_and(|prefix| prefix.is_empty() || prefix.ends_with('.')),
+      Self::Wildcard(pattern) => wildcard_match(pattern.as_bytes(), host.as_bytes())
+    }
+  }
+}
+
+impl FromStr for DomainPattern {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let s = s.trim().trim_end_matches('.').to_ascii_lowercase();
+    if s.is_empty() || s.contains(|c: char| c.is_whitespace() || c == '/') { bail!("wrong domain: {s}"); }
+    Ok(if s.contains('*') { Self::Wildcard(s) } else { Self::Suffix(s) })
+  }
+}
+
+fn wildcard_match(pattern: &[u8], host: &[u8]) -> bool {
+  match pattern.split_first() {
+    None => host.is_empty(),
+    Some((b'*', rest)) => (0..=host.len()).any(|i| wildcard_match(rest, &host[i..])),
+    Some((c, rest)) => host.first() == Some(c) && wildcard_match(rest, &host[1..])
+  }
+}
+
+/// What is done with matched connection
+#[derive(Clone, Debug)]
+pub enum Action {
+  Desync(Box<BypassOptions>),
+  NoDesync
+}
+
+#[derive(Clone)]
+pub struct Rule {
+  pub domains: Vec<DomainPattern>,
+  pub nets: Vec<IpNet>,
+  pub action: Action
+}
+
+impl Rule {
+  pub fn new(action: Action) -> Self { Self{ domains: Vec::new(), nets: Vec::new(), action } }
+
+  /// Adds entries of hostlist file: domain patterns, IPs or CIDRs, one per line. Lines starting with '#' are skipped
+  pub fn load_hostlist(&mut self, path: &Path) -> Result<(), anyhow::Error> {
+    let data = std::fs::read_to_string(path).with_context(|| format!("can't read hostlist {}", path.display()))?;
+    for line in data.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+      match line.parse::<IpNet>() {
+        Ok(net) => self.nets.push(net),
+        Err(_) => self.domains.push(line.parse().with_context(|| format!("wrong hostlist {}", path.display()))?)
+      }
+    }
+    Ok(())
+  }
+
+  pub fn matches(&self, host: Option<&str>, ip: Option<IpAddr>) -> bool {
+    host.is_some_and(|host| self.domains.iter().any(|domain| domain.matches(host)))
+      || ip.is_some_and(|ip| self.nets.iter().any(|net| net.contains(ip)))
+  }
+}
+
+impl fmt::Debug for Rule {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    // hostlists can be huge
+    f.debug_struct("Rule")
+      .field("domains", &self.domains.len())
+      .field("nets", &self.nets.len())
+      .field("action", &self.action)
+      .finish()
+  }
+}
+
+/// Rules are checked in order, the first matched rule selects action for connection
+#[derive(Clone, Debug, Default)]
+pub struct Rules(pub Vec<Rule>);
+
+impl Rules {
+  pub fn select(&self, host: Option<&str>, ip: Option<IpAddr>) -> Option<&Action> {
+    let host = host.map(|host| host.trim_end_matches('.').to_ascii_lowercase());
+    self.0.iter().find(|rule| rule.matches(host.as_deref(), ip)).map(|rule| &rule.action)
+  }
+
+  pub fn is_empty(&self) -> bool { self.0.is_empty() }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn match_domains() {
+    let suffix: DomainPattern = "Example.com.".parse().unwrap();
+    assert!(suffix.matches("example.com"));
+    assert!(suffix.matches("www.example.com"));
+    assert!(!suffix.matches("badexample.com"));
+    let wildcard: DomainPattern = "rr*.googlevideo.com".parse().unwrap();
+    assert!(wildcard.matches("rr3---sn-abc.googlevideo.com"));
+    assert!(!wildcard.matches("googlevideo.com"));
+    assert!("*.example.com".parse::<DomainPattern>().unwrap().matches("a.example.com"));
+    assert!(!"*.example.com".parse::<DomainPattern>().unwrap().matches("example.com"));
+  }
+
+  #[test]
+  fn match_nets() {
+    let net: IpNet = "10.1.0.0/15".parse().unwrap();
+    assert!(net.contains("10.0.255.1".parse().unwrap()));
+    assert!(!net.contains("10.2.0.1".parse().unwrap()));
+    assert!(net.contains("::ffff:10.1.2.3".parse().unwrap()));
+    let net: IpNet = "2001:db8::/32".parse().unwrap();
+    assert!(net.contains("2001:db8:1::1".parse().unwrap()));
+    assert!(!net.contains("2001:db9::1".parse().unwrap()));
+    assert!("1.2.3.4".parse::<IpNet>().unwrap().contains("1.2.3.4".parse().unwrap()));
+    assert!("1.2.3.4/33".parse::<IpNet>().is_err());
+  }
+
+  #[test]
+  fn select_first_matched_rule() {
+    let mut no_desync = Rule::new(Action::NoDesync);
+    no_desync.domains.push("bank.example".parse().unwrap());
+    let mut desync = Rule::new(Action::Desync(Box::new(BypassOptions::new())));
+    desync.nets.push("192.0.2.0/24".parse().unwrap());
+    let rules = Rules(vec![no_desync, desync]);
+    assert!(matches!(rules.select(Some("WWW.Bank.Example"), "192.0.2.1".parse().ok()), Some(Action::NoDesync)));
+    assert!(matches!(rules.select(Some("example.org"), "192.0.2.1".parse().ok()), Some(Action::Desync(_))));
+    assert!(rules.select(Some("example.org"), "198.51.100.1".parse().ok()).is_none());
+  }
+}
@@ -0,0 +1,97 @@
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::process;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+
+use log::{info, warn};
+use tokio::sync::Notify;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static NOTIFY: Notify = Notify::const_new();
+/// Becomes readable when shutdown is requested, it is polled by threads without tokio runtime
+static EVENT_FD: OnceLock<OwnedFd> = OnceLock::new();
+
+fn signal_set() -> libc::sigset_t {
+  unsafe {
+    let mut set = MaybeUninit::<libc::sigset_t>::uninit();
+    libc::sigemptyset(set.as_mut_ptr());
+    libc::sigaddset(set.as_mut_ptr(), libc::SIGINT);
+    libc::sigaddset(set.as_mut_ptr(), libc::SIGTERM);
+    set.assume_init()
+  }
+}
+
+/// Blocks SIGINT and SIGTERM and waits for them in separate thread. Must be called before other threads are spawned,
+/// so they inherit the mask. The first signal requests shutdown, the second one exits immediately
+pub fn handle_signals() -> io::Result<()> {
+  let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+  if fd < 0 { return Err(io::Error::last_os_error()); }
+  let _ = EVENT_FD.set(unsafe { OwnedFd::from_raw_fd(fd) });
+  let set = signal_set();
+  let ret = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) };
+  if ret != 0 { return Err(io::Error::from_raw_os_error(ret)); }
+  thread::Builder::new().name("signals".into()).spawn(move || {
+    let mut sig = 0;
+    while unsafe { libc::sigwait(&set, &mut sig) } == 0 {
+      if is_requested() {
+        warn!("signal {sig} is received again, exiting without draining");
+        process::exit(128 + sig);
+      }
+      info!("signal {sig} is received, shutting down");
+      request();
+    }
+  })?;
+  Ok(())
+}
+
+/// Stops listeners and udp desync. Called on signal or when part of rustpass can't work anymore
+pub fn request() {
+  if REQUESTED.swap(true, Ordering::SeqCst) { return; }
+  NOTIFY.notify_waiters();
+  if let Some(fd) = EVENT_FD.get() {
+    let one = 1u64;
+    unsafe { libc::write(fd.as_raw_fd(), &one as *const u64 as _, 8) };
+  }
+}
+
+pub fn is_requested() -> bool {
+  REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Completes when shutdown is requested
+pub async fn requested() {
+  let notified = NOTIFY.notified();
+  tokio::pin!(notified);
+  // waiter is registered before the check, so notification after it isn't missed
+  notified.as_mut().enable();
+  if is_requested() { return; }
+  notified.await;
+}
+
+/// Fd which becomes readable when shutdown is requested, -1 if signals aren't handled
+#[cfg_attr(not(feature = "udp-desync"), allow(dead_code))]
+pub fn event_fd() -> RawFd {
+  EVENT_FD.get().map_or(-1, |fd| fd.as_raw_fd())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn request_wakes_waiters() {
+    tokio_uring::start(async {
+      let waiter = tokio_uring::spawn(requested());
+      tokio::task::yield_now().await;
+      assert!(!waiter.is_finished());
+      request();
+      waiter.await.unwrap();
+      // requested after shutdown completes at once
+      requested().await;
+    });
+  }
+}
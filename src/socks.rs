@@ -1,12 +1,61 @@
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::io;
+use std::ops::Range;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd};
 
-use anyhow::bail;
-use tokio_uring::net::TcpStream;
+use anyhow::{anyhow, bail};
+use log::debug;
+use socket2::Socket;
+use tokio::net::lookup_host;
+use tokio_uring::buf::BoundedBuf;
+use tokio_uring::net::{TcpStream, UdpSocket};
+
+use crate::udp_relay::UdpRelay;
 
 pub const SOCKS4_VERSION: u8 = 4u8;
 pub const SOCKS4_CONNECT_COMMAND: u8 = 1u8;
 pub const SOCKS4_BIND_COMMAND: u8 = 2u8;
 
+pub const SOCKS5_VERSION: u8 = 5u8;
+pub const SOCKS5_NO_AUTH_METHOD: u8 = 0u8;
+pub const SOCKS5_NO_ACCEPTABLE_METHODS: u8 = 0xffu8;
+pub const SOCKS5_CONNECT_COMMAND: u8 = 1u8;
+pub const SOCKS5_UDP_ASSOCIATE_COMMAND: u8 = 3u8;
+pub const SOCKS5_ATYP_IPV4: u8 = 1u8;
+pub const SOCKS5_ATYP_DOMAIN: u8 = 3u8;
+pub const SOCKS5_ATYP_IPV6: u8 = 4u8;
+pub const SOCKS5_REP_SUCCEEDED: u8 = 0u8;
+pub const SOCKS5_REP_FAILURE: u8 = 1u8;
+pub const SOCKS5_REP_NETWORK_UNREACHABLE: u8 = 3u8;
+pub const SOCKS5_REP_HOST_UNREACHABLE: u8 = 4u8;
+pub const SOCKS5_REP_CONNECTION_REFUSED: u8 = 5u8;
+pub const SOCKS5_REP_COMMAND_NOT_SUPPORTED: u8 = 7u8;
+pub const SOCKS5_REP_ADDR_TYPE_NOT_SUPPORTED: u8 = 8u8;
+
+#[derive(Debug, Clone)]
+pub enum SocksAddr {
+  Ip(SocketAddr),
+  Domain(String, u16)
+}
+
+impl SocksAddr {
+  pub fn domain(&self) -> Option<&str> {
+    match self {
+      Self::Domain(host, _) => Some(host),
+      Self::Ip(_) => None
+    }
+  }
+
+  pub async fn resolve(&self) -> io::Result<SocketAddr> {
+    match self {
+      Self::Ip(addr) => Ok(*addr),
+      Self::Domain(host, port) => lookup_host((host.as_str(), *port)).await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("cannot resolve {host}")))
+    }
+  }
+}
+
 #[derive(Debug)]
 pub enum Socks4Phase {
   ConnectReq,
@@ -16,32 +65,54 @@ pub enum Socks4Phase {
 
 pub struct Socks4 {
   pub phase: Socks4Phase,
-  pub proxy_addr: SocketAddr,
+  pub proxy_addr: SocksAddr,
+  pub userid: String,
+  /// Length of the request, data after it was sent by client before reply
+  pub req_len: usize,
   pub proxy_stream: Option<TcpStream>,
   pub client_stream: TcpStream
 }
 
 impl Socks4 {
+  /// Client can send data right after the request without waiting for reply, so input may be longer than the request
   pub fn is_connect_req(input: &[u8], client_stream: TcpStream) -> Result<Self, anyhow::Error> {
-    if input.len() < 8 || input.len() > 30 {
+    let (proxy_addr, userid, req_len) = Socks4::parse_req(input)?;
+    Ok(Self{
+      phase: Socks4Phase::ConnectReq,
+      proxy_addr,
+      userid,
+      req_len,
+      proxy_stream: None,
+      client_stream
+    })
+  }
+
+  /// Returns destination, userid and length of the request
+  fn parse_req(input: &[u8]) -> Result<(SocksAddr, String, usize), anyhow::Error> {
+    if input.len() < 9 {
       bail!("it isn't fisrt socks packet because len: {}", input.len());
     }
-    match input[0] {
-      SOCKS4_VERSION => {
-        if input[1] != SOCKS4_CONNECT_COMMAND && input[1] != SOCKS4_BIND_COMMAND {
-          bail!("it isn't fisrt socks{} packet", input[0]);
-        }
-        let port = u16::from_be_bytes([input[2], input[3]]);
-        let ip = Ipv4Addr::new(input[4], input[5], input[6], input[7]);
-        let dst = SocketAddrV4::new(ip, port);
-        Ok(Self{
-          phase: Socks4Phase::ConnectReq,
-          proxy_addr: SocketAddr::from(dst),
-          proxy_stream: None,
-          client_stream
-        })
-      }
-      ver => bail!("unsupported socks version: {ver}")
+    if input[0] != SOCKS4_VERSION { bail!("unsupported socks version: {}", input[0]); }
+    if input[1] != SOCKS4_CONNECT_COMMAND && input[1] != SOCKS4_BIND_COMMAND {
+      bail!("it isn't fisrt socks{} packet", input[0]);
+    }
+    let port = u16::from_be_bytes([input[2], input[3]]);
+    let ip = Ipv4Addr::new(input[4], input[5], input[6], input[7]);
+    let Some(userid_len) = input[8..].iter().position(|&b| b == 0) else {
+      bail!("socks4 userid isn't null terminated");
+    };
+    let userid = String::from_utf8_lossy(&input[8..8 + userid_len]).into_owned();
+    let rest = &input[9 + userid_len..];
+    // SOCKS4a: ip 0.0.0.x with x != 0 means that the hostname follows the userid
+    if ip.octets()[..3] == [0, 0, 0] && ip.octets()[3] != 0 {
+      let Some(host_len) = rest.iter().position(|&b| b == 0) else {
+        bail!("socks4a hostname isn't null terminated");
+      };
+      if host_len == 0 { bail!("socks4a hostname is empty"); }
+      let host = std::str::from_utf8(&rest[..host_len])?;
+      Ok((SocksAddr::Domain(host.to_owned(), port), userid, 9 + userid_len + host_len + 1))
+    } else {
+      Ok((SocksAddr::Ip(SocketAddr::from(SocketAddrV4::new(ip, port))), userid, 9 + userid_len))
     }
   }
 
@@ -49,8 +120,13 @@ impl Socks4 {
     assert!(input.len() >= 8, "input len must be >= 8 given: {}", input.len());
     match &self.phase {
       Socks4Phase::ConnectReq => {
-        match TcpStream::connect(self.proxy_addr).await {
+        let connected = match self.proxy_addr.resolve().await {
+          Ok(dst) => TcpStream::connect(dst).await,
+          Err(e) => Err(e)
+        };
+        match connected {
           Ok(pr_stream) => {
+            debug!("socks4 connected to {:?}, userid: {:?}", self.proxy_addr, self.userid);
             let (res, _) = self.client_stream.write(vec![0u8, 90, input[2], input[3], input[4], input[5], input[6], input[7]])
               .submit()
               .await; res?;
@@ -70,3 +146,338 @@ impl Socks4 {
     Ok(())
   }
 }
+
+#[derive(Debug)]
+pub enum Socks5Phase {
+  MethodReq,
+  ConnectReq,
+  ConnectRep,
+  Proxing
+}
+
+pub struct Socks5 {
+  pub phase: Socks5Phase,
+  pub command: u8,
+  pub proxy_addr: Option<SocksAddr>,
+  pub proxy_stream: Option<TcpStream>,
+  pub client_stream: TcpStream
+}
+
+impl Socks5 {
+  /// Method request may be split between reads, it is completed by read_method_req
+  pub fn is_method_req(input: &[u8], client_stream: TcpStream) -> Result<Self, anyhow::Error> {
+    if input.is_empty() {
+      bail!("it isn't fisrt socks5 packet because len: {}", input.len());
+    }
+    match input[0] {
+      SOCKS5_VERSION => Ok(Self{
+        phase: Socks5Phase::MethodReq,
+        command: SOCKS5_CONNECT_COMMAND,
+        proxy_addr: None,
+        proxy_stream: None,
+        client_stream
+      }),
+      ver => bail!("unsupported socks version: {ver}")
+    }
+  }
+
+  pub async fn reply_method(&mut self, input: &[u8]) -> Result<(), anyhow::Error> {
+    match &self.phase {
+      Socks5Phase::MethodReq => {
+        let no_auth = input[2..].contains(&SOCKS5_NO_AUTH_METHOD);
+        let method = if no_auth { SOCKS5_NO_AUTH_METHOD } else { SOCKS5_NO_ACCEPTABLE_METHODS };
+        let (res, _) = self.client_stream.write(vec![SOCKS5_VERSION, method]).submit().await; res?;
+        if !no_auth { bail!("socks5 client doesn't support no auth method"); }
+        self.phase = Socks5Phase::ConnectReq;
+      }
+      socks_phase => bail!("Socks5 Phase for method reply must be {:?}, but current: {:?}", Socks5Phase::MethodReq, socks_phase)
+    }
+    Ok(())
+  }
+
+  /// Length of the method request, None if more bytes are needed to know it
+  pub fn method_req_len(input: &[u8]) -> Option<usize> {
+    input.get(1).map(|&nmethods| 2 + nmethods as usize)
+  }
+
+  /// Reads until message of msg_len is complete, buf already has n bytes. Returns buffer, length of message
+  /// and number of bytes in buffer, the client can send next message without waiting for reply
+  async fn read_msg(&self, mut buf: Vec<u8>, mut n: usize, msg_len: fn(&[u8]) -> Option<usize>,
+                    msg: &str) -> Result<(Vec<u8>, usize, usize), anyhow::Error> {
+    loop {
+      match msg_len(&buf[..n]) {
+        Some(len) if len <= n => return Ok((buf, len, n)),
+        _ if n == buf.len() => bail!("socks5 {msg} doesn't fit in buffer of {} bytes", buf.len()),
+        _ => {}
+      }
+      let (result, slice) = self.client_stream.read(buf.slice(n..)).await;
+      buf = slice.into_inner();
+      match result? {
+        0 => bail!("socks5 client closed connection before {msg}, got {n} bytes"),
+        read => n += read
+      }
+    }
+  }
+
+  /// Reads the method request until it is complete, buf already has n bytes of it.
+  /// Returns buffer, length of the method request and number of bytes in buffer
+  pub async fn read_method_req(&self, buf: Vec<u8>, n: usize) -> Result<(Vec<u8>, usize, usize), anyhow::Error> {
+    self.read_msg(buf, n, Socks5::method_req_len, "method request").await
+  }
+
+  /// Reads the request until it is complete, buf already has n bytes of it received with the method request.
+  /// Returns buffer and range of data received after the request, it is the first data of the client
+  pub async fn read_req(&mut self, buf: Vec<u8>, n: usize) -> Result<(Vec<u8>, Range<usize>), anyhow::Error> {
+    let (buf, req_len, n) = self.read_msg(buf, n, Socks5::req_len, "request").await?;
+    let (command, addr) = match Socks5::parse_req(&buf[..req_len]) {
+      Ok(req) => req,
+      Err((rep, e)) => {
+        self.reply(rep, None).await?;
+        return Err(e);
+      }
+    };
+    debug!("socks5 request with command {command} to {addr:?}");
+    self.command = command;
+    self.proxy_addr = Some(addr);
+    Ok((buf, req_len..n))
+  }
+
+  /// Length of the request, None if more bytes are needed to know it.
+  /// With unknown address type it is the length before address, so parse_req reports the type
+  fn req_len(input: &[u8]) -> Option<usize> {
+    let addr_len = match *input.get(3)? {
+      SOCKS5_ATYP_IPV4 => 4,
+      SOCKS5_ATYP_IPV6 => 16,
+      SOCKS5_ATYP_DOMAIN => 1 + *input.get(4)? as usize,
+      _ => return Some(4)
+    };
+    Some(4 + addr_len + 2)
+  }
+
+  pub fn parse_req(input: &[u8]) -> Result<(u8, SocksAddr), (u8, anyhow::Error)> {
+    if input.len() < 4 { return Err((SOCKS5_REP_FAILURE, anyhow!("socks5 request is too short: {}", input.len()))); }
+    if input[0] != SOCKS5_VERSION { return Err((SOCKS5_REP_FAILURE, anyhow!("unsupported socks version: {}", input[0]))); }
+    if input[1] != SOCKS5_CONNECT_COMMAND && input[1] != SOCKS5_UDP_ASSOCIATE_COMMAND {
+      return Err((SOCKS5_REP_COMMAND_NOT_SUPPORTED, anyhow!("unsupported socks5 command: {}", input[1])));
+    }
+    let (addr, addr_len) = Socks5::parse_addr(&input[3..])?;
+    if input.len() != 3 + addr_len {
+      return Err((SOCKS5_REP_FAILURE, anyhow!("wrong socks5 request len: {}", input.len())));
+    }
+    Ok((input[1], addr))
+  }
+
+  /// Parses ATYP, DST.ADDR and DST.PORT fields, returns address and count of parsed bytes
+  pub fn parse_addr(input: &[u8]) -> Result<(SocksAddr, usize), (u8, anyhow::Error)> {
+    if input.is_empty() { return Err((SOCKS5_REP_FAILURE, anyhow!("socks5 address without type"))); }
+    let (addr_len, addr_start) = match input[0] {
+      SOCKS5_ATYP_IPV4 => (4, 1),
+      SOCKS5_ATYP_IPV6 => (16, 1),
+      SOCKS5_ATYP_DOMAIN if input.len() > 1 => (input[1] as usize, 2),
+      SOCKS5_ATYP_DOMAIN => return Err((SOCKS5_REP_FAILURE, anyhow!("socks5 request without domain len"))),
+      atyp => return Err((SOCKS5_REP_ADDR_TYPE_NOT_SUPPORTED, anyhow!("unsupported socks5 address type: {atyp}")))
+    };
+    let port_start = addr_start + addr_len;
+    if input.len() < port_start + 2 {
+      return Err((SOCKS5_REP_FAILURE, anyhow!("socks5 address is too short: {}", input.len())));
+    }
+    let addr = &input[addr_start..port_start];
+    let port = u16::from_be_bytes([input[port_start], input[port_start + 1]]);
+    let addr = match input[0] {
+      SOCKS5_ATYP_IPV4 => SocksAddr::Ip(SocketAddr::new(IpAddr::from(<[u8; 4]>::try_from(addr).unwrap()), port)),
+      SOCKS5_ATYP_IPV6 => SocksAddr::Ip(SocketAddr::new(IpAddr::from(<[u8; 16]>::try_from(addr).unwrap()), port)),
+      _ => {
+        let host = std::str::from_utf8(addr)
+          .map_err(|e| (SOCKS5_REP_FAILURE, anyhow::Error::new(e)))?;
+        SocksAddr::Domain(host.to_owned(), port)
+      }
+    };
+    Ok((addr, port_start + 2))
+  }
+
+  pub async fn connect_to_dst(&mut self) -> Result<(), anyhow::Error> {
+    match (&self.phase, &self.proxy_addr) {
+      (Socks5Phase::ConnectReq, Some(addr)) => {
+        let connected = match addr.resolve().await {
+          Ok(dst) => TcpStream::connect(dst).await,
+          Err(e) => Err(e)
+        };
+        match connected {
+          Ok(pr_stream) => {
+            let bind_addr = local_addr(&pr_stream)?;
+            self.reply(SOCKS5_REP_SUCCEEDED, Some(bind_addr)).await?;
+            self.proxy_stream = Some(pr_stream);
+            self.phase = Socks5Phase::ConnectRep;
+          }
+          Err(e) => {
+            self.reply(socks5_rep_from_err(&e), None).await?;
+            return Err(anyhow::Error::new(e));
+          }
+        }
+      }
+      (socks_phase, _) => bail!("Socks5 Phase for connect must be {:?}, but current: {:?}", Socks5Phase::ConnectReq, socks_phase)
+    }
+    Ok(())
+  }
+
+  /// Binds udp socket for relaying and replies with its address.
+  /// Returns relay socket and client udp address from request
+  pub async fn udp_associate(&mut self) -> Result<(UdpSocket, SocketAddr), anyhow::Error> {
+    match (&self.phase, &self.proxy_addr) {
+      (Socks5Phase::ConnectReq, Some(addr)) => {
+        let client_ip = peer_addr(&self.client_stream)?.ip();
+        let client_addr = match addr {
+          SocksAddr::Ip(addr) if !addr.ip().is_unspecified() => *addr,
+          SocksAddr::Ip(addr) => SocketAddr::new(client_ip, addr.port()),
+          SocksAddr::Domain(_, port) => SocketAddr::new(client_ip, *port)
+        };
+        let local_ip = local_addr(&self.client_stream)?.ip();
+        match UdpRelay::bind() {
+          Ok(socket) => {
+            let bind_addr = SocketAddr::new(local_ip, socket.local_addr()?.port());
+            self.reply(SOCKS5_REP_SUCCEEDED, Some(bind_addr)).await?;
+            self.phase = Socks5Phase::ConnectRep;
+            Ok((socket, client_addr))
+          }
+          Err(e) => {
+            self.reply(socks5_rep_from_err(&e), None).await?;
+            Err(anyhow::Error::new(e))
+          }
+        }
+      }
+      (socks_phase, _) => bail!("Socks5 Phase for udp associate must be {:?}, but current: {:?}", Socks5Phase::ConnectReq, socks_phase)
+    }
+  }
+
+  /// Parses header of udp datagram from client, returns destination and header len
+  pub fn parse_udp_header(input: &[u8]) -> Result<(SocksAddr, usize), anyhow::Error> {
+    if input.len() < 4 { bail!("socks5 udp datagram is too short: {}", input.len()); }
+    if input[2] != 0 { bail!("socks5 udp fragmentation isn't supported, frag: {}", input[2]); }
+    let (addr, addr_len) = Socks5::parse_addr(&input[3..]).map_err(|(_, e)| e)?;
+    Ok((addr, 3 + addr_len))
+  }
+
+  pub fn udp_header(src: SocketAddr) -> Vec<u8> {
+    let mut out = vec![0, 0, 0];
+    push_addr(&mut out, src);
+    out
+  }
+
+  async fn reply(&self, rep: u8, bind_addr: Option<SocketAddr>) -> io::Result<()> {
+    let mut out = vec![SOCKS5_VERSION, rep, 0];
+    push_addr(&mut out, bind_addr.unwrap_or(SocketAddr::from(([0, 0, 0, 0], 0))));
+    let (res, _) = self.client_stream.write(out).submit().await;
+    res.map(|_| ())
+  }
+}
+
+fn push_addr(out: &mut Vec<u8>, addr: SocketAddr) {
+  match addr {
+    SocketAddr::V4(addr) => {
+      out.push(SOCKS5_ATYP_IPV4);
+      out.extend_from_slice(&addr.ip().octets());
+    }
+    SocketAddr::V6(addr) => {
+      out.push(SOCKS5_ATYP_IPV6);
+      out.extend_from_slice(&addr.ip().octets());
+    }
+  }
+  out.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+fn socks5_rep_from_err(e: &io::Error) -> u8 {
+  match e.raw_os_error() {
+    Some(libc::ENETUNREACH) => SOCKS5_REP_NETWORK_UNREACHABLE,
+    Some(libc::EHOSTUNREACH) | Some(libc::ETIMEDOUT) => SOCKS5_REP_HOST_UNREACHABLE,
+    Some(libc::ECONNREFUSED) => SOCKS5_REP_CONNECTION_REFUSED,
+    _ if e.kind() == io::ErrorKind::NotFound => SOCKS5_REP_HOST_UNREACHABLE,
+    _ => SOCKS5_REP_FAILURE
+  }
+}
+
+pub fn local_addr(stream: &TcpStream) -> io::Result<SocketAddr> {
+  let sock = unsafe { Socket::from_raw_fd(stream.as_raw_fd()) };
+  let ret = sock.local_addr();
+  let _ = sock.into_raw_fd();
+  ret?.as_socket().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an inet socket"))
+}
+
+pub fn peer_addr(stream: &TcpStream) -> io::Result<SocketAddr> {
+  let sock = unsafe { Socket::from_raw_fd(stream.as_raw_fd()) };
+  let ret = sock.peer_addr();
+  let _ = sock.into_raw_fd();
+  ret?.as_socket().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an inet socket"))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::Ipv6Addr;
+
+  use super::*;
+
+  fn parse(input: &[u8]) -> Result<(u8, SocksAddr), u8> {
+    Socks5::parse_req(input).map_err(|(rep, _)| rep)
+  }
+
+  #[test]
+  fn parse_socks5_requests() {
+    let ipv4 = [5, 1, 0, 1, 127, 0, 0, 1, 1, 187];
+    assert!(matches!(parse(&ipv4), Ok((SOCKS5_CONNECT_COMMAND, SocksAddr::Ip(addr))) if addr == "127.0.0.1:443".parse().unwrap()));
+    let mut ipv6 = vec![5, 3, 0, 4];
+    ipv6.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+    ipv6.extend_from_slice(&[0, 53]);
+    assert!(matches!(parse(&ipv6), Ok((SOCKS5_UDP_ASSOCIATE_COMMAND, SocksAddr::Ip(addr))) if addr == "[2001:db8::1]:53".parse().unwrap()));
+    let domain = b"\x05\x01\x00\x03\x0bexample.com\x00\x50";
+    assert!(matches!(parse(domain), Ok((_, SocksAddr::Domain(host, 80))) if host == "example.com"));
+    for len in 0..ipv4.len() {
+      assert_eq!(parse(&ipv4[..len]).unwrap_err(), SOCKS5_REP_FAILURE);
+    }
+    assert_eq!(parse(&domain[..domain.len() - 1]).unwrap_err(), SOCKS5_REP_FAILURE);
+    assert_eq!(parse(&[5, 1, 0, 2, 1, 2, 3, 4, 0, 80]).unwrap_err(), SOCKS5_REP_ADDR_TYPE_NOT_SUPPORTED);
+    assert_eq!(parse(&[5, 2, 0, 1, 1, 2, 3, 4, 0, 80]).unwrap_err(), SOCKS5_REP_COMMAND_NOT_SUPPORTED);
+    assert_eq!(parse(&[5, 1, 0, 3, 2, 0xff, 0xfe, 0, 80]).unwrap_err(), SOCKS5_REP_FAILURE);
+  }
+
+  #[test]
+  fn socks5_request_len() {
+    assert_eq!(Socks5::req_len(&[5, 1, 0]), None);
+    assert_eq!(Socks5::req_len(&[5, 1, 0, 1]), Some(10));
+    assert_eq!(Socks5::req_len(&[5, 1, 0, 4]), Some(22));
+    assert_eq!(Socks5::req_len(&[5, 1, 0, 3]), None);
+    assert_eq!(Socks5::req_len(b"\x05\x01\x00\x03\x0bexample.com\x00\x50\x16\x03\x01"), Some(18));
+    assert_eq!(Socks5::req_len(&[5, 1, 0, 9]), Some(4));
+    assert_eq!(Socks5::method_req_len(&[5]), None);
+    assert_eq!(Socks5::method_req_len(&[5, 2, 0, 1, 5, 1, 0, 1]), Some(4));
+  }
+
+  #[test]
+  fn parse_socks4_requests() {
+    let (addr, userid, len) = Socks4::parse_req(b"\x04\x01\x01\xbb\x7f\x00\x00\x01user\0\x16\x03\x01").unwrap();
+    assert!(matches!(addr, SocksAddr::Ip(addr) if addr == "127.0.0.1:443".parse().unwrap()));
+    assert_eq!((userid.as_str(), len), ("user", 13));
+    let (addr, _, len) = Socks4::parse_req(b"\x04\x01\x00\x50\x00\x00\x00\x01\0example.com\0GET").unwrap();
+    assert!(matches!(addr, SocksAddr::Domain(host, 80) if host == "example.com"));
+    assert_eq!(len, 21);
+    assert!(Socks4::parse_req(b"\x04\x01\x00\x50\x00\x00\x00\x01\0example.com").is_err());
+    assert!(Socks4::parse_req(b"\x04\x01\x00\x50\x7f\x00\x00\x01user").is_err());
+    assert!(Socks4::parse_req(b"\x04\x03\x00\x50\x7f\x00\x00\x01\0").is_err());
+  }
+
+  #[test]
+  fn udp_header_round_trip() {
+    for src in ["1.2.3.4:53", "[2001:db8::1]:443"] {
+      let src: SocketAddr = src.parse().unwrap();
+      let mut datagram = Socks5::udp_header(src);
+      let header_len = datagram.len();
+      datagram.extend_from_slice(b"payload");
+      let (addr, len) = Socks5::parse_udp_header(&datagram).unwrap();
+      assert!(matches!(addr, SocksAddr::Ip(addr) if addr == src));
+      assert_eq!((len, &datagram[len..]), (header_len, &b"payload"[..]));
+    }
+    let (addr, len) = Socks5::parse_udp_header(b"\0\0\0\x03\x0bexample.com\x01\xbbdata").unwrap();
+    assert!(matches!(addr, SocksAddr::Domain(host, 443) if host == "example.com"));
+    assert_eq!(len, 18);
+    assert!(Socks5::parse_udp_header(&[0, 0, 1, 1, 1, 2, 3, 4, 0, 53]).is_err());
+    assert!(Socks5::parse_udp_header(&[0, 0, 0, 1, 1, 2]).is_err());
+  }
+}
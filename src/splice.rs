@@ -0,0 +1,134 @@
+use std::cell::Cell;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::rc::Rc;
+use std::time::Instant;
+
+use log::trace;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+
+/// Max bytes moved by one splice call, it is the default pipe capacity
+const SPLICE_LEN: usize = 1 << 16;
+
+/// Duplicate of socket fd owned by tokio_uring stream, registered in tokio reactor for readiness.
+/// tokio_uring closes its fd asynchronously, own fd can't be reused while it is registered
+pub struct SockFd(OwnedFd);
+
+impl AsRawFd for SockFd {
+  fn as_raw_fd(&self) -> RawFd { self.0.as_raw_fd() }
+}
+
+/// Both directions of relay share returned AsyncFd
+pub fn register(fd: RawFd) -> io::Result<Rc<AsyncFd<SockFd>>> {
+  let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+  if dup < 0 { return Err(io::Error::last_os_error()); }
+  let sock = SockFd(unsafe { OwnedFd::from_raw_fd(dup) });
+  Ok(Rc::new(AsyncFd::with_interest(sock, Interest::READABLE | Interest::WRITABLE)?))
+}
+
+/// Waits until socket has data or EOF without reading it. Unlike io_uring read, it can be cancelled without loss of data
+/// and buffer for data isn't needed while waiting
+pub async fn wait_data(sock: &AsyncFd<SockFd>) -> io::Result<()> {
+  loop {
+    let mut guard = sock.readable().await?;
+    let res = guard.try_io(|sock| {
+      let mut byte = 0u8;
+      let ret = unsafe { libc::recv(sock.as_raw_fd(), &mut byte as *mut u8 as _, 1, libc::MSG_PEEK | libc::MSG_DONTWAIT) };
+      if ret < 0 { Err(io::Error::last_os_error()) } else { Ok(()) }
+    });
+    if let Ok(res) = res { return res; }
+  }
+}
+
+/// Splice to socket blocks without O_NONBLOCK, io_uring reads and writes fail with it.
+/// So it is set only when the socket isn't used by io_uring anymore. Flag is shared with duplicates of fd
+pub fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+  let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+  if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(())
+}
+
+pub struct Pipe {
+  read: OwnedFd,
+  write: OwnedFd
+}
+
+impl Pipe {
+  pub fn new() -> io::Result<Self> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { Self{ read: OwnedFd::from_raw_fd(fds[0]), write: OwnedFd::from_raw_fd(fds[1]) } })
+  }
+}
+
+fn splice(fd_in: RawFd, fd_out: RawFd, len: usize) -> io::Result<usize> {
+  let ret = unsafe {
+    libc::splice(fd_in, ptr::null_mut(), fd_out, ptr::null_mut(), len, libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK)
+  };
+  if ret < 0 { Err(io::Error::last_os_error()) } else { Ok(ret as usize) }
+}
+
+/// Moves data from one socket to another through the pipe inside kernel until EOF.
+/// Both sockets must be nonblocking. Time of the last moved data is saved to active. Returns number of relayed bytes
+pub async fn splice_one_side(from: Rc<AsyncFd<SockFd>>, to: Rc<AsyncFd<SockFd>>, pipe: Pipe, active: &Cell<Instant>) -> io::Result<u64> {
+  let mut total = 0;
+  loop {
+    let n = loop {
+      let mut guard = from.readable().await?;
+      if let Ok(res) = guard.try_io(|from| splice(from.as_raw_fd(), pipe.write.as_raw_fd(), SPLICE_LEN)) { break res?; }
+    };
+    if n == 0 { break; }
+    let mut left = n;
+    while left > 0 {
+      let mut guard = to.writable().await?;
+      if let Ok(res) = guard.try_io(|to| splice(pipe.read.as_raw_fd(), to.as_raw_fd(), left)) { left -= res?; }
+    }
+    total += n as u64;
+    active.set(Instant::now());
+  }
+  trace!("{total} bytes were spliced from fd {} to fd {}", from.as_raw_fd(), to.as_raw_fd());
+  Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::{Read, Write};
+  use std::net::{TcpListener, TcpStream};
+  use std::os::fd::AsRawFd;
+  use std::thread;
+
+  use super::*;
+
+  #[test]
+  fn splice_between_sockets() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let data: Vec<u8> = (0..1_000_000u32).map(|i| i as u8).collect();
+    let expected = data.clone();
+    let writer = thread::spawn(move || TcpStream::connect(addr).unwrap().write_all(&data).unwrap());
+    let (from, _) = listener.accept().unwrap();
+    let mut reader = TcpStream::connect(addr).unwrap();
+    let (to, _) = listener.accept().unwrap();
+    let reader = thread::spawn(move || {
+      let mut received = Vec::new();
+      reader.read_to_end(&mut received).unwrap();
+      received
+    });
+    tokio_uring::start(async {
+      set_nonblocking(from.as_raw_fd()).unwrap();
+      set_nonblocking(to.as_raw_fd()).unwrap();
+      let active = Cell::new(Instant::now());
+      let total = splice_one_side(register(from.as_raw_fd()).unwrap(), register(to.as_raw_fd()).unwrap(), Pipe::new().unwrap(), &active).await;
+      assert_eq!(total.unwrap(), expected.len() as u64);
+    });
+    drop(to);
+    writer.join().unwrap();
+    assert!(reader.join().unwrap() == expected);
+  }
+}
@@ -0,0 +1,275 @@
+use std::ops::Range;
+
+use anyhow::bail;
+
+pub const TLS_RECORD_HEADER_LEN: usize = 5;
+pub const TLS_MAX_RECORD_LEN: usize = TLS_RECORD_HEADER_LEN + (1 << 14);
+const TLS_EXT_SERVER_NAME: u16 = 0;
+const TLS_EXT_ALPN: u16 = 16;
+const TLS_EXT_SUPPORTED_VERSIONS: u16 = 43;
+const SNI_HOST_NAME_TYPE: u8 = 0;
+
+pub fn is_tls_chello(input: &[u8]) -> bool {
+  input.len() > 5 && u16::from_be_bytes([input[0], input[1]]) == 0x1603 && input[5] == 1
+}
+
+pub fn is_tls_server_hello(input: &[u8]) -> bool {
+  input.len() > 5 && input[0] == 0x16 && input[1] == 3 && input[5] == 2
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Sni {
+  pub host: String,
+  /// Position of host name bytes in the parsed input
+  pub range: Range<usize>
+}
+
+/// Parsed view of TLS ClientHello.
+/// Fields which are outside of input are left empty and `truncated` is set
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientHello {
+  pub record_version: u16,
+  pub record_len: usize,
+  pub handshake_len: usize,
+  pub legacy_version: u16,
+  pub sni: Option<Sni>,
+  pub alpn: Vec<String>,
+  pub supported_versions: Vec<u16>,
+  /// Position of extensions block without its len
+  pub extensions: Range<usize>,
+  pub truncated: bool
+}
+
+impl ClientHello {
+  /// Returns None if input isn't a start of TLS handshake record with ClientHello
+  pub fn parse(input: &[u8]) -> Option<Self> {
+    if !is_tls_chello(input) { return None; }
+    let mut chello = ClientHello {
+      record_version: u16::from_be_bytes([input[1], input[2]]),
+      ..Default::default()
+    };
+    chello.truncated = chello.parse_fields(input).is_none();
+    Some(chello)
+  }
+
+  /// Len of the whole record including header
+  pub fn full_record_len(&self) -> usize { TLS_RECORD_HEADER_LEN + self.record_len }
+
+  fn parse_fields(&mut self, input: &[u8]) -> Option<()> {
+    let mut cur = Cursor{ input, pos: 3 };
+    self.record_len = cur.u16()? as usize;
+    cur.skip(1)?;
+    self.handshake_len = cur.u24()?;
+    self.legacy_version = cur.u16()?;
+    cur.skip(32)?;
+    let session_id_len = cur.u8()? as usize;
+    cur.skip(session_id_len)?;
+    let cipher_suites_len = cur.u16()? as usize;
+    cur.skip(cipher_suites_len)?;
+    let compression_len = cur.u8()? as usize;
+    cur.skip(compression_len)?;
+    let extensions_len = cur.u16()? as usize;
+    let extensions_end = cur.pos + extensions_len;
+    self.extensions = cur.pos..extensions_end;
+    while cur.pos < extensions_end {
+      let ext_type = cur.u16()?;
+      let ext_len = cur.u16()? as usize;
+      let ext_end = cur.pos + ext_len;
+      match ext_type {
+        TLS_EXT_SERVER_NAME => self.sni = Some(parse_sni(&mut cur, ext_end)?),
+        TLS_EXT_ALPN => self.alpn = parse_alpn(&mut cur, ext_end)?,
+        TLS_EXT_SUPPORTED_VERSIONS => self.supported_versions = parse_supported_versions(&mut cur, ext_end)?,
+        _ => {}
+      }
+      cur.pos = ext_end;
+    }
+    (cur.pos <= input.len()).then_some(())
+  }
+}
+
+fn parse_sni(cur: &mut Cursor, ext_end: usize) -> Option<Sni> {
+  cur.skip(2)?;
+  while cur.pos < ext_end {
+    let name_type = cur.u8()?;
+    let name_len = cur.u16()? as usize;
+    let name = cur.bytes(name_len)?;
+    if name_type == SNI_HOST_NAME_TYPE {
+      return Some(Sni{ host: String::from_utf8_lossy(name).into_owned(), range: cur.pos - name_len..cur.pos });
+    }
+  }
+  Some(Sni::default())
+}
+
+fn parse_alpn(cur: &mut Cursor, ext_end: usize) -> Option<Vec<String>> {
+  let mut alpn = Vec::new();
+  cur.skip(2)?;
+  while cur.pos < ext_end {
+    let proto_len = cur.u8()? as usize;
+    alpn.push(String::from_utf8_lossy(cur.bytes(proto_len)?).into_owned());
+  }
+  Some(alpn)
+}
+
+fn parse_supported_versions(cur: &mut Cursor, ext_end: usize) -> Option<Vec<u16>> {
+  let mut versions = Vec::new();
+  cur.skip(1)?;
+  while cur.pos < ext_end {
+    versions.push(cur.u16()?);
+  }
+  Some(versions)
+}
+
+/// Splits the first TLS record of buf[..size] into many records at positions.
+/// Positions outside of record payload are skipped, data which isn't TLS handshake record is left as is.
+/// Returns new size of data in buf and positions which were applied
+pub fn split_record(buf: &mut Vec<u8>, size: usize, positions: &[usize]) -> (usize, Vec<usize>) {
+  if size < TLS_RECORD_HEADER_LEN || buf[0] != 0x16 || buf[1] != 3 { return (size, Vec::new()); }
+  let record_end = TLS_RECORD_HEADER_LEN + u16::from_be_bytes([buf[3], buf[4]]) as usize;
+  let mut applied: Vec<usize> = positions.iter()
+    .copied()
+    .filter(|&pos| pos > TLS_RECORD_HEADER_LEN && pos < record_end.min(size))
+    .collect();
+  applied.sort_unstable();
+  applied.dedup();
+  if applied.is_empty() { return (size, applied); }
+  let mut out = Vec::with_capacity(size + applied.len() * TLS_RECORD_HEADER_LEN);
+  let mut start = TLS_RECORD_HEADER_LEN;
+  for &end in applied.iter().chain(std::iter::once(&record_end)) {
+    out.extend_from_slice(&buf[..3]);
+    out.extend_from_slice(&((end - start) as u16).to_be_bytes());
+    out.extend_from_slice(&buf[start..end.min(size)]);
+    start = end;
+  }
+  if record_end < size { out.extend_from_slice(&buf[record_end..size]); }
+  if buf.len() < out.len() { buf.resize(out.len(), 0); }
+  buf[..out.len()].copy_from_slice(&out);
+  (out.len(), applied)
+}
+
+/// Returns copy of ClientHello template with replaced SNI host name and fixed lens
+pub fn chello_with_sni(template: &[u8], host: &str) -> Result<Vec<u8>, anyhow::Error> {
+  let Some(chello) = ClientHello::parse(template).filter(|chello| !chello.truncated) else {
+    bail!("wrong ClientHello template");
+  };
+  let Some(sni) = chello.sni.as_ref().filter(|sni| !sni.range.is_empty()) else { bail!("ClientHello template without SNI"); };
+  if host.is_empty() || host.len() > u8::MAX as usize { bail!("wrong SNI host name len: {}", host.len()); }
+  let delta = host.len() as isize - sni.range.len() as isize;
+  let mut out = Vec::with_capacity(template.len() + host.len());
+  out.extend_from_slice(&template[..sni.range.start]);
+  out.extend_from_slice(host.as_bytes());
+  out.extend_from_slice(&template[sni.range.end..chello.full_record_len()]);
+  // record, handshake, extensions, SNI extension, server name list and host name lens
+  add_to_len(&mut out[3..5], delta);
+  add_to_len(&mut out[6..9], delta);
+  add_to_len(&mut out[chello.extensions.start - 2..chello.extensions.start], delta);
+  add_to_len(&mut out[sni.range.start - 7..sni.range.start - 5], delta);
+  add_to_len(&mut out[sni.range.start - 5..sni.range.start - 3], delta);
+  add_to_len(&mut out[sni.range.start - 2..sni.range.start], delta);
+  Ok(out)
+}
+
+/// Adds delta to big endian len field
+fn add_to_len(field: &mut [u8], delta: isize) {
+  let len = field.iter().fold(0isize, |len, &b| len << 8 | b as isize) + delta;
+  let field_len = field.len();
+  field.iter_mut().enumerate().for_each(|(i, b)| *b = (len >> (8 * (field_len - 1 - i))) as u8);
+}
+
+struct Cursor<'a> {
+  input: &'a [u8],
+  pos: usize
+}
+
+impl<'a> Cursor<'a> {
+  fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+    let bytes = self.input.get(self.pos..self.pos + len)?;
+    self.pos += len;
+    Some(bytes)
+  }
+
+  fn skip(&mut self, len: usize) -> Option<()> { self.bytes(len).map(|_| ()) }
+
+  fn u8(&mut self) -> Option<u8> { self.bytes(1).map(|b| b[0]) }
+
+  fn u16(&mut self) -> Option<u16> { self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]])) }
+
+  fn u24(&mut self) -> Option<usize> { self.bytes(3).map(|b| u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize) }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::bypass::FAKE_TLS;
+
+  #[test]
+  fn parse_full_chello() {
+    let chello = ClientHello::parse(&FAKE_TLS).unwrap();
+    assert!(!chello.truncated);
+    assert_eq!(chello.record_len, 512);
+    assert_eq!(chello.full_record_len(), FAKE_TLS.len());
+    assert_eq!(chello.handshake_len, 508);
+    assert_eq!(chello.legacy_version, 0x0303);
+    let sni = chello.sni.unwrap();
+    assert_eq!(sni.host, "www.wikipedia.org");
+    assert_eq!(&FAKE_TLS[sni.range], b"www.wikipedia.org");
+    assert_eq!(chello.alpn, vec!["h2", "http/1.1"]);
+    assert_eq!(chello.supported_versions, vec![0x0304, 0x0303, 0x0302, 0x0301]);
+  }
+
+  #[test]
+  fn parse_truncated_chello() {
+    let chello = ClientHello::parse(&FAKE_TLS[..200]).unwrap();
+    assert!(chello.truncated);
+    assert_eq!(chello.record_len, 512);
+    assert_eq!(chello.sni.unwrap().host, "www.wikipedia.org");
+    assert!(chello.alpn.is_empty());
+
+    let chello = ClientHello::parse(&FAKE_TLS[..20]).unwrap();
+    assert!(chello.truncated);
+    assert_eq!(chello.handshake_len, 508);
+    assert!(chello.sni.is_none());
+  }
+
+  #[test]
+  fn split_chello_record() {
+    let mut buf = FAKE_TLS.to_vec();
+    let (size, applied) = split_record(&mut buf, FAKE_TLS.len(), &[100, 3, 50, 100, 600]);
+    assert_eq!(applied, vec![50, 100]);
+    assert_eq!(size, FAKE_TLS.len() + 2 * TLS_RECORD_HEADER_LEN);
+    let mut payload = Vec::new();
+    let mut pos = 0;
+    while pos < size {
+      assert_eq!(buf[pos..pos + 3], FAKE_TLS[..3]);
+      let len = u16::from_be_bytes([buf[pos + 3], buf[pos + 4]]) as usize;
+      payload.extend_from_slice(&buf[pos + TLS_RECORD_HEADER_LEN..pos + TLS_RECORD_HEADER_LEN + len]);
+      pos += TLS_RECORD_HEADER_LEN + len;
+    }
+    assert_eq!(pos, size);
+    assert_eq!(payload, FAKE_TLS[TLS_RECORD_HEADER_LEN..]);
+
+    let http = b"GET / HTTP/1.1\r\n\r\n";
+    let mut buf = http.to_vec();
+    assert_eq!(split_record(&mut buf, http.len(), &[7, 10]), (http.len(), Vec::new()));
+    assert_eq!(buf, http);
+  }
+
+  #[test]
+  fn generate_chello_with_sni() {
+    for host in ["a.io", "www.wikipedia.org", "very-long-subdomain.of.some-example-domain.com"] {
+      let data = chello_with_sni(&FAKE_TLS, host).unwrap();
+      let chello = ClientHello::parse(&data).unwrap();
+      assert!(!chello.truncated);
+      assert_eq!(chello.full_record_len(), data.len());
+      assert_eq!(chello.handshake_len + 4, chello.record_len);
+      assert_eq!(chello.sni.unwrap().host, host);
+      assert_eq!(chello.alpn, vec!["h2", "http/1.1"]);
+      assert_eq!(chello.extensions.end, data.len());
+    }
+  }
+
+  #[test]
+  fn parse_not_chello() {
+    assert!(ClientHello::parse(b"GET / HTTP/1.1\r\n\r\n").is_none());
+    assert!(ClientHello::parse(&FAKE_TLS[..5]).is_none());
+  }
+}
@@ -0,0 +1,97 @@
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, RawFd};
+
+use log::{trace, warn};
+use socket2::{Domain, SockAddr, Socket, Type};
+use tokio_uring::net::{TcpListener, TcpStream};
+
+use crate::socks::local_addr;
+
+/// Binds listener with IP_TRANSPARENT, so it can accept connections redirected by TPROXY.
+/// Connections redirected by REDIRECT/DNAT are accepted by it as well
+pub fn bind_transparent(addr: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+  let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+  socket.set_reuse_address(true)?;
+  socket.set_reuse_port(reuse_port)?;
+  let (level, opt) = if addr.is_ipv4() { (libc::SOL_IP, libc::IP_TRANSPARENT) } else { (libc::SOL_IPV6, libc::IPV6_TRANSPARENT) };
+  if let Err(e) = set_int_opt(socket.as_raw_fd(), level, opt, 1) {
+    // without CAP_NET_ADMIN only REDIRECT/DNAT rules can be used
+    warn!("cannot set transparent option, TPROXY won't work: {e}");
+  }
+  socket.bind(&addr.into())?;
+  socket.listen(1024)?;
+  Ok(TcpListener::from_std(socket.into()))
+}
+
+/// Returns destination of redirected connection. It is SO_ORIGINAL_DST for REDIRECT/DNAT
+/// and local address of accepted socket for TPROXY
+pub fn original_dst(stream: &TcpStream, listen_addr: SocketAddr) -> io::Result<SocketAddr> {
+  let local = local_addr(stream)?;
+  let fd = stream.as_raw_fd();
+  let nat_dst = if local.is_ipv6() {
+    get_original_dst(fd, libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST)
+      .or_else(|_| get_original_dst(fd, libc::SOL_IP, libc::SO_ORIGINAL_DST))
+  } else { get_original_dst(fd, libc::SOL_IP, libc::SO_ORIGINAL_DST) };
+  let dst = match nat_dst {
+    Ok(dst) => dst,
+    Err(e) => {
+      trace!("SO_ORIGINAL_DST failed: {e}, using local address");
+      local
+    }
+  };
+  if dst == local && (local == listen_addr || (local.port() == listen_addr.port() && listen_addr.ip().is_unspecified())) {
+    return Err(io::Error::new(io::ErrorKind::InvalidInput, "connection wasn't redirected to transparent proxy"));
+  }
+  Ok(dst)
+}
+
+fn get_original_dst(fd: RawFd, level: libc::c_int, opt: libc::c_int) -> io::Result<SocketAddr> {
+  let (_, addr) = unsafe {
+    SockAddr::try_init(|storage, len| {
+      if libc::getsockopt(fd, level, opt, storage.cast(), len) < 0 { Err(io::Error::last_os_error()) }
+      else { Ok(()) }
+    })
+  }?;
+  addr.as_socket().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an inet address"))
+}
+
+fn set_int_opt(fd: RawFd, level: libc::c_int, opt: libc::c_int, val: libc::c_int) -> io::Result<()> {
+  let ret = unsafe {
+    libc::setsockopt(fd, level, opt, &val as *const libc::c_int as _, std::mem::size_of::<libc::c_int>() as _)
+  };
+  if ret < 0 { return Err(io::Error::last_os_error()); }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn direct_connection_is_not_redirected() {
+    tokio_uring::start(async {
+      // without CAP_NET_ADMIN transparent option isn't set, but listener still works for REDIRECT/DNAT
+      let listener = bind_transparent("127.0.0.1:0".parse().unwrap(), false).unwrap();
+      let listen_addr = listener.local_addr().unwrap();
+      let client = TcpStream::connect(listen_addr).await.unwrap();
+      let (stream, peer) = listener.accept().await.unwrap();
+      assert_eq!(peer, local_addr(&client).unwrap());
+      let err = original_dst(&stream, listen_addr).unwrap_err();
+      assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+      // with TPROXY the local address of accepted socket differs from listen addr
+      let other: SocketAddr = "0.0.0.0:1".parse().unwrap();
+      assert_eq!(original_dst(&stream, other).unwrap(), listen_addr);
+    });
+  }
+
+  #[test]
+  fn bind_transparent_with_reuse_port() {
+    tokio_uring::start(async {
+      let first = bind_transparent("127.0.0.1:0".parse().unwrap(), true).unwrap();
+      let addr = first.local_addr().unwrap();
+      assert!(bind_transparent(addr, true).is_ok());
+      assert!(bind_transparent(addr, false).is_err());
+    });
+  }
+}
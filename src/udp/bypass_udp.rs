@@ -2,14 +2,15 @@ use core::marker::{PhantomData, PhantomPinned};
 
 use std::fmt::{self, Debug};
 use std::ptr::null_mut;
+use std::os::fd::RawFd;
 use std::os::raw::c_char;
 
 use anyhow::bail;
 use libc::__errno_location;
 
+use crate::udp_relay::{FAKE_PKT_LEN, FAKE_UDP_PKT};
+
 pub const UDP_RECV_BUF_SIZE: usize = 2048;
-const FAKE_PKT_LEN: usize = 64;
-static FAKE_UDP_PKT: [u8; FAKE_PKT_LEN] = [0; FAKE_PKT_LEN];
 
 #[repr(C)]
 struct NfqHandle {
@@ -38,7 +39,7 @@ extern "C" {
   fn init_nfq(bypass_data: *const BypassData, h: *mut *mut NfqHandle, qh: *mut *mut NfqQHandle) -> i32;
 
   #[allow(improper_ctypes)]
-  fn run_nfq(h: *mut NfqHandle, buf: *mut c_char, buf_size: usize);
+  fn run_nfq(h: *mut NfqHandle, buf: *mut c_char, buf_size: usize, stop_fd: RawFd);
 
   #[allow(improper_ctypes)]
   fn destroy_nfq(h: *mut NfqHandle, qh: *mut NfqQHandle);
@@ -80,9 +81,10 @@ impl UdpBypassHelpData {
     Ok(())
   }
 
-  pub fn run_nfq_loop(mut self) {
+  /// Handles packets until stop_fd becomes readable or queue fails, then queue is destroyed
+  pub fn run_nfq_loop(mut self, stop_fd: RawFd) {
     unsafe {
-      run_nfq(self.h, self.buf.as_mut_ptr() as *mut c_char, self.buf.len());
+      run_nfq(self.h, self.buf.as_mut_ptr() as *mut c_char, self.buf.len(), stop_fd);
     }
   }
 }
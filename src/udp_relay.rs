@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::Range;
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd};
+use std::rc::Rc;
+
+use log::{trace, debug};
+use socket2::{Domain, Socket, Type};
+use tokio_uring::buf::BoundedBuf;
+use tokio_uring::net::UdpSocket;
+
+use crate::bypass::{FakePayload, FAKE_TLS};
+use crate::socks::{Socks5, SocksAddr};
+
+pub const FAKE_PKT_LEN: usize = 64;
+pub static FAKE_UDP_PKT: [u8; FAKE_PKT_LEN] = [0; FAKE_PKT_LEN];
+/// Resolved domains of one association, client chooses them, so cache is cleared when it is full
+const MAX_RESOLVED: usize = 256;
+
+/// Socks5 udp associate relay. Every datagram from client is sent to destination after fake datagram with fake_ttl.
+/// Relay socket is dual-stack if IPv6 is available, so clients of both families reach destinations of both families
+pub struct UdpRelay {
+  socket: Rc<UdpSocket>,
+  /// IPv4 addresses are used as v4-mapped by dual-stack socket
+  dual_stack: bool,
+  client_addr: SocketAddr,
+  fake_ttl: u32,
+  /// None is FAKE_UDP_PKT
+  fake_payload: Option<FakePayload>,
+  resolved: HashMap<(String, u16), SocketAddr>
+}
+
+impl UdpRelay {
+  /// client_addr with port 0 means that client udp port is unknown and will be taken from the first valid datagram
+  pub fn new(socket: UdpSocket, client_addr: SocketAddr, fake_ttl: u32, fake_payload: Option<FakePayload>) -> Self {
+    let dual_stack = socket.local_addr().is_ok_and(|addr| addr.is_ipv6());
+    Self{socket: Rc::new(socket), dual_stack, client_addr: canonical(client_addr), fake_ttl, fake_payload, resolved: HashMap::new()}
+  }
+
+  /// Binds dual-stack socket to [::]:0 or IPv4 socket to 0.0.0.0:0 if IPv6 is disabled
+  pub fn bind() -> io::Result<UdpSocket> {
+    let dual_stack = Socket::new(Domain::IPV6, Type::DGRAM, None).and_then(|socket| {
+      socket.set_only_v6(false)?;
+      socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)).into())?;
+      Ok(socket)
+    });
+    let socket = match dual_stack {
+      Ok(socket) => socket,
+      Err(e) => {
+        debug!("cannot bind dual-stack udp socket: {e}");
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)).into())?;
+        socket
+      }
+    };
+    Ok(UdpSocket::from_std(socket.into()))
+  }
+
+  pub async fn run(mut self, mut buf: Vec<u8>) -> Result<(), anyhow::Error> {
+    loop {
+      let (result, nbuf) = self.socket.recv_from(buf).await;
+      buf = nbuf;
+      let (size, src) = result?;
+      let src = canonical(src);
+      if self.is_from_client(src) {
+        let (dst, header_len) = match Socks5::parse_udp_header(&buf[..size]) {
+          Ok(header) => header,
+          Err(e) => { debug!("dropping udp datagram from client: {e}"); continue; }
+        };
+        // other ports of client ip aren't accepted after the first valid datagram
+        if self.client_addr.port() == 0 {
+          debug!("udp relay client addr: {src}");
+          self.client_addr = src;
+        }
+        let dst = match self.resolve(dst).await {
+          Ok(dst) => dst,
+          Err(e) => { debug!("dropping udp datagram from client: {e}"); continue; }
+        };
+        let (res, nbuf) = self.send_with_fake(buf, header_len..size, dst).await;
+        buf = nbuf;
+        if let Err(e) = res { debug!("cannot send udp datagram to {dst}: {e}"); }
+      } else if self.client_addr.port() != 0 {
+        let mut datagram = Socks5::udp_header(src);
+        datagram.extend_from_slice(&buf[..size]);
+        let (res, _) = self.socket.send_to(datagram, self.socket_addr(self.client_addr)).await; res?;
+        trace!("udp relay: {size} bytes from {src} to client");
+      }
+    }
+  }
+
+  fn is_from_client(&self, src: SocketAddr) -> bool {
+    src.ip() == self.client_addr.ip() && (self.client_addr.port() == 0 || src.port() == self.client_addr.port())
+  }
+
+  async fn resolve(&mut self, addr: SocksAddr) -> io::Result<SocketAddr> {
+    match addr {
+      SocksAddr::Ip(addr) => Ok(addr),
+      SocksAddr::Domain(host, port) => {
+        if let Some(addr) = self.resolved.get(&(host.clone(), port)) { return Ok(*addr); }
+        let addr = SocksAddr::Domain(host.clone(), port).resolve().await?;
+        if self.resolved.len() >= MAX_RESOLVED { self.resolved.clear(); }
+        self.resolved.insert((host, port), addr);
+        Ok(addr)
+      }
+    }
+  }
+
+  /// Address for send_to of relay socket
+  fn socket_addr(&self, addr: SocketAddr) -> SocketAddr {
+    match addr {
+      SocketAddr::V4(v4) if self.dual_stack => SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()),
+      addr => addr
+    }
+  }
+
+  async fn send_with_fake(&self, buf: Vec<u8>, range: Range<usize>, dst: SocketAddr) -> (io::Result<()>, Vec<u8>) {
+    let dst = canonical(dst);
+    let orig_ttl = match self.swap_ttl(self.fake_ttl, dst.is_ipv4()) {
+      Ok(ttl) => ttl,
+      Err(e) => return (Err(e), buf)
+    };
+    let (res, _) = self.socket.send_to(self.fake_datagram(), self.socket_addr(dst)).await;
+    if let Err(e) = self.swap_ttl(orig_ttl, dst.is_ipv4()).and(res) { return (Err(e), buf); }
+    let size = range.len();
+    let (res, slice) = self.socket.send_to(buf.slice(range), self.socket_addr(dst)).await;
+    trace!("udp relay: {size} bytes from client to {dst}");
+    (res.map(|_| ()), slice.into_inner())
+  }
+
+  fn fake_datagram(&self) -> Vec<u8> {
+    match &self.fake_payload {
+      None => FAKE_UDP_PKT.to_vec(),
+      Some(FakePayload::Default) => FAKE_TLS.to_vec(),
+      Some(FakePayload::Data(data)) => data.to_vec(),
+      Some(payload) => payload.bytes(FAKE_PKT_LEN)
+    }
+  }
+
+  /// Sets TTL for IPv4 destinations or hop limit for IPv6 ones and returns the previous value.
+  /// Dual-stack socket has both, IPv4 TTL is used for v4-mapped destinations
+  fn swap_ttl(&self, ttl: u32, ipv4: bool) -> io::Result<u32> {
+    let sock = unsafe { Socket::from_raw_fd(self.socket.as_raw_fd()) };
+    let ret = if ipv4 { sock.ttl().and_then(|orig| sock.set_ttl(ttl).map(|_| orig)) }
+      else { sock.unicast_hops_v6().and_then(|orig| sock.set_unicast_hops_v6(ttl).map(|_| orig)) };
+    let _ = sock.into_raw_fd();
+    ret
+  }
+}
+
+/// Dual-stack socket receives from IPv4 peers with v4-mapped addresses
+fn canonical(addr: SocketAddr) -> SocketAddr {
+  SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::UdpSocket as StdUdpSocket;
+  use std::sync::Arc;
+  use std::thread;
+  use std::time::Duration;
+
+  use super::*;
+
+  /// IPv4 client sends to IPv6 and IPv4 destinations through one relay socket
+  #[test]
+  fn relay_between_families() {
+    let client = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    let client_addr = client.local_addr().unwrap();
+    tokio_uring::start(async {
+      let socket = UdpRelay::bind().unwrap();
+      let relay_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, socket.local_addr().unwrap().port()));
+      let handle = tokio_uring::spawn(UdpRelay::new(socket, client_addr, 8, None).run(vec![0u8; 2048]));
+      let exchange = thread::spawn(move || {
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        for dst in ["[::1]:0", "127.0.0.1:0"] {
+          let dst = StdUdpSocket::bind(dst).unwrap();
+          dst.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+          let dst_addr = dst.local_addr().unwrap();
+          let mut datagram = Socks5::udp_header(dst_addr);
+          datagram.extend_from_slice(b"request");
+          client.send_to(&datagram, relay_addr).unwrap();
+          let mut buf = [0u8; 2048];
+          let (n, _) = dst.recv_from(&mut buf).unwrap();
+          assert_eq!(&buf[..n], &FAKE_UDP_PKT[..]);
+          let (n, relay) = dst.recv_from(&mut buf).unwrap();
+          assert_eq!(&buf[..n], b"request");
+          dst.send_to(b"response", relay).unwrap();
+          let (n, _) = client.recv_from(&mut buf).unwrap();
+          let (src, header_len) = Socks5::parse_udp_header(&buf[..n]).unwrap();
+          assert!(matches!(src, SocksAddr::Ip(src) if src == dst_addr));
+          assert_eq!(&buf[header_len..n], b"response");
+        }
+      });
+      while !exchange.is_finished() { tokio::time::sleep(Duration::from_millis(10)).await; }
+      handle.abort();
+      exchange.join().unwrap();
+    });
+  }
+
+  /// Client announced port 0, the port is pinned by its first datagram
+  #[test]
+  fn pin_client_port() {
+    let client = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    let other = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    let other_addr = other.local_addr().unwrap();
+    let dst = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    let dst_addr = dst.local_addr().unwrap();
+    tokio_uring::start(async {
+      let socket = UdpRelay::bind().unwrap();
+      let relay_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, socket.local_addr().unwrap().port()));
+      let fake = FakePayload::Data(Arc::new(b"fake".to_vec()));
+      let relay = UdpRelay::new(socket, SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), 8, Some(fake));
+      let handle = tokio_uring::spawn(relay.run(vec![0u8; 2048]));
+      let exchange = thread::spawn(move || {
+        for sock in [&client, &other, &dst] { sock.set_read_timeout(Some(Duration::from_secs(2))).unwrap(); }
+        let mut buf = [0u8; 2048];
+        // invalid datagram doesn't pin the port
+        other.send_to(b"junk", relay_addr).unwrap();
+        let mut datagram = Socks5::udp_header(dst_addr);
+        datagram.extend_from_slice(b"request");
+        client.send_to(&datagram, relay_addr).unwrap();
+        let (n, _) = dst.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"fake");
+        let (n, _) = dst.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"request");
+        // another port of client ip is treated as remote peer, so it can't send through the relay
+        other.send_to(&datagram, relay_addr).unwrap();
+        let (n, _) = client.recv_from(&mut buf).unwrap();
+        let (src, header_len) = Socks5::parse_udp_header(&buf[..n]).unwrap();
+        assert!(matches!(src, SocksAddr::Ip(src) if src == other_addr));
+        assert_eq!(&buf[header_len..n], &datagram[..]);
+      });
+      while !exchange.is_finished() { tokio::time::sleep(Duration::from_millis(10)).await; }
+      handle.abort();
+      exchange.join().unwrap();
+    });
+  }
+}
@@ -6,6 +6,11 @@ if [ -f "/tmp/IP_FORWARD_BEFORE" ]; then
 else
   IP_FORWARD_BEFORE=$(sysctl net.ipv4.ip_forward --values)
 fi
+if [ -f "/tmp/IP6_FORWARD_BEFORE" ]; then
+  IP6_FORWARD_BEFORE=$(cat /tmp/IP6_FORWARD_BEFORE)
+else
+  IP6_FORWARD_BEFORE=$(sysctl net.ipv6.conf.all.forwarding --values)
+fi
 
 prepare_ns() {
   local mark=$1
@@ -22,10 +27,17 @@ prepare_ns() {
   ip netns exec ns1 ip link set ceth0 up
   ip netns exec ns1 ip addr add 17.0.0.10/24 dev ceth0
   ip netns exec ns1 ip route add default via 17.0.0.1
+  ip addr add fd17::1/64 dev br0
+  ip netns exec ns1 ip addr add fd17::10/64 dev ceth0 nodad
+  ip netns exec ns1 ip -6 route add default via fd17::1
   sysctl -w net.ipv4.ip_forward=1
+  sysctl -w net.ipv6.conf.all.forwarding=1
   iptables -t nat -A POSTROUTING -s 17.0.0.0/24 ! -o br0 -j MASQUERADE
+  ip6tables -t nat -A POSTROUTING -s fd17::/64 ! -o br0 -j MASQUERADE
   ip netns exec ns1 iptables -I OUTPUT -o ceth0 -p udp -m mark ! --mark $mark -j NFQUEUE --queue-num $queue_num
+  ip netns exec ns1 ip6tables -I OUTPUT -o ceth0 -p udp -m mark ! --mark $mark -j NFQUEUE --queue-num $queue_num
   echo $IP_FORWARD_BEFORE > /tmp/IP_FORWARD_BEFORE
+  echo $IP6_FORWARD_BEFORE > /tmp/IP6_FORWARD_BEFORE
 }
 
 del_ns() {
@@ -39,6 +51,7 @@ del_ns() {
   ip link set br0 down
   ip link delete br0
   sysctl -w net.ipv4.ip_forward=$IP_FORWARD_BEFORE
+  sysctl -w net.ipv6.conf.all.forwarding=$IP6_FORWARD_BEFORE
 }
 
 if [ $(id -u) -ne 0 ]; then